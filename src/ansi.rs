@@ -0,0 +1,177 @@
+//! Parsing existing ANSI art back into characters and colors
+
+use crate::palette::Rgb;
+
+/// Color of text that hasn't been colored
+pub const DEFAULT_COLOR: Rgb = (255, 255, 255);
+
+/// Furthest cursor forward can move in one sequence, as terminals stop at their right margin
+const MAX_CURSOR_FORWARD: usize = 1024;
+
+/// The 16 standard terminal colors
+const STANDARD: [Rgb; 16] = [
+    (0, 0, 0), (128, 0, 0), (0, 128, 0), (128, 128, 0),
+    (0, 0, 128), (128, 0, 128), (0, 128, 128), (192, 192, 192),
+    (128, 128, 128), (255, 0, 0), (0, 255, 0), (255, 255, 0),
+    (0, 0, 255), (255, 0, 255), (0, 255, 255), (255, 255, 255),
+];
+
+/// Color `index` of the xterm 256 color palette
+fn xterm(index: u8) -> Rgb {
+    match index {
+        0..=15 => STANDARD[index as usize],
+        16..=231 => {
+            let level = |v: u8| if v == 0 { 0 } else { 55 + v * 40 };
+            let i = index - 16;
+            (level(i / 36), level(i / 6 % 6), level(i % 6))
+        }
+        _ => {
+            let gray = 8 + (index - 232) * 10;
+            (gray, gray, gray)
+        }
+    }
+}
+
+/// Apply the SGR parameters `params` to the foreground color `fg`
+fn apply_sgr(params: &[u32], fg: &mut Rgb) {
+    let mut params = params.iter().copied();
+    while let Some(param) = params.next() {
+        match param {
+            0 | 39 => *fg = DEFAULT_COLOR,
+            30..=37 => *fg = STANDARD[(param - 30) as usize],
+            90..=97 => *fg = STANDARD[(param - 90 + 8) as usize],
+            38 => match params.next() {
+                Some(5) => {
+                    if let Some(index) = params.next() {
+                        *fg = xterm(index.min(255) as u8);
+                    }
+                }
+                Some(2) => {
+                    let mut channel = || params.next().unwrap_or(0).min(255) as u8;
+                    *fg = (channel(), channel(), channel());
+                }
+                _ => {}
+            },
+            // Skip over the arguments of extended background colors
+            48 => match params.next() {
+                Some(5) => { params.next(); }
+                Some(2) => { params.nth(2); }
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+}
+
+/// Split `text` into rows of characters and their foreground colors, ignoring
+/// every escape sequence other than colors and cursor forward
+pub fn parse(text: &str) -> Vec<Vec<(char, Rgb)>> {
+    let mut rows = vec![Vec::new()];
+    let mut fg = DEFAULT_COLOR;
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\x1b' => {
+                match chars.next() {
+                    Some('[') => {}
+                    // OSC, DCS, SOS, PM and APC strings run until BEL or ST
+                    Some(']') | Some('P') | Some('X') | Some('^') | Some('_') => {
+                        while let Some(c) = chars.next() {
+                            if c == '\x07' || (c == '\x1b' && chars.next_if_eq(&'\\').is_some()) {
+                                break;
+                            }
+                        }
+                        continue;
+                    }
+                    // Other escapes are intermediate bytes followed by a final byte
+                    Some(mut c) => {
+                        while (' '..='/').contains(&c) {
+                            match chars.next() {
+                                Some(next) => c = next,
+                                None => break
+                            }
+                        }
+                        continue;
+                    }
+                    None => continue
+                }
+
+                let mut sequence = String::new();
+                let mut end = None;
+                for c in chars.by_ref() {
+                    if ('@'..='~').contains(&c) {
+                        end = Some(c);
+                        break;
+                    }
+                    sequence.push(c);
+                }
+
+                let params = sequence
+                    .split(';')
+                    .map(|param| param.parse::<u32>().unwrap_or(0))
+                    .collect::<Vec<u32>>();
+                match end {
+                    Some('m') => apply_sgr(&params, &mut fg),
+                    // Skipped columns are left blank
+                    Some('C') => {
+                        let n = (params[0].max(1) as usize).min(MAX_CURSOR_FORWARD);
+                        let row = rows.last_mut().unwrap();
+                        row.resize(row.len() + n, (' ', DEFAULT_COLOR));
+                    }
+                    _ => {}
+                }
+            }
+            '\n' => rows.push(Vec::new()),
+            '\r' | '\x0c' => {}
+            c if c.is_control() => {}
+            c => rows.last_mut().unwrap().push((c, fg))
+        }
+    }
+
+    // Drop the row after a trailing newline
+    if rows.len() > 1 && rows.last().is_some_and(|row| row.is_empty()) {
+        rows.pop();
+    }
+
+    let width = rows.iter().map(|row| row.len()).max().unwrap_or(0);
+    for row in rows.iter_mut() {
+        row.resize(width, (' ', DEFAULT_COLOR));
+    }
+
+    rows
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(rows: &[Vec<(char, Rgb)>]) -> Vec<String> {
+        rows.iter().map(|row| row.iter().map(|(c, _)| *c).collect()).collect()
+    }
+
+    #[test]
+    fn colors() {
+        let rows = parse("\x1b[31ma\x1b[38;5;21mb\x1b[38;2;1;2;3mc\x1b[0md");
+        let colors = rows[0].iter().map(|(_, color)| *color).collect::<Vec<Rgb>>();
+        assert_eq!(colors, vec![(128, 0, 0), (0, 0, 255), (1, 2, 3), DEFAULT_COLOR]);
+    }
+
+    #[test]
+    fn skips_strings_and_short_escapes() {
+        let rows = parse("\x1b]0;title\x07a\x1b]2;other\x1b\\b\x1bPdata\x1b\\c\x1b(Bd\x1b7e\x1b=f");
+        assert_eq!(text(&rows), vec!["abcdef"]);
+    }
+
+    #[test]
+    fn cursor_forward_leaves_blanks() {
+        let rows = parse("\x1b[5Cxy\n\x1b[Cz");
+        assert_eq!(text(&rows), vec!["     xy", " z     "]);
+    }
+
+    #[test]
+    fn pads_rows() {
+        let rows = parse("ab\nc\n");
+        assert_eq!(text(&rows), vec!["ab", "c "]);
+    }
+}
@@ -0,0 +1,19 @@
+//! Braille patterns, each drawing a 2 by 4 grid of dots
+
+/// Dots across a braille cell
+pub const COLS: u32 = 2;
+/// Dots down a braille cell
+pub const ROWS: u32 = 4;
+
+/// Bit of the braille pattern for each dot, row by row
+const DOT_BITS: [u32; (COLS * ROWS) as usize] = [0x01, 0x08, 0x02, 0x10, 0x04, 0x20, 0x40, 0x80];
+
+/// Braille character raising the dots set in `dots`, row by row.
+/// A cell without dots is a space so it's treated like any other blank cell.
+pub fn from_dots(dots: &[bool; (COLS * ROWS) as usize]) -> char {
+    let bits = dots.iter().zip(DOT_BITS.iter())
+        .filter(|(dot, _)| **dot)
+        .fold(0, |bits, (_, bit)| bits | bit);
+
+    if bits == 0 { ' ' } else { std::char::from_u32(0x2800 + bits).unwrap() }
+}
@@ -0,0 +1,155 @@
+//! Dithering thresholds and error diffusion
+
+use std::str::FromStr;
+
+/// 4x4 Bayer matrix
+const BAYER_4: [[u8; 4]; 4] = [
+    [0, 8, 2, 10],
+    [12, 4, 14, 6],
+    [3, 11, 1, 9],
+    [15, 7, 13, 5],
+];
+
+/// Share of the error passed to each (dx, dy) neighbour by Floyd-Steinberg
+const FLOYD_STEINBERG: &[(i64, i64, f64)] = &[
+    (1, 0, 7.0 / 16.0), (-1, 1, 3.0 / 16.0), (0, 1, 5.0 / 16.0), (1, 1, 1.0 / 16.0),
+];
+
+/// Share of the error passed to each (dx, dy) neighbour by Atkinson, which drops a quarter of it
+const ATKINSON: &[(i64, i64, f64)] = &[
+    (1, 0, 1.0 / 8.0), (2, 0, 1.0 / 8.0), (-1, 1, 1.0 / 8.0), (0, 1, 1.0 / 8.0), (1, 1, 1.0 / 8.0), (0, 2, 1.0 / 8.0),
+];
+
+/// Ordered dithering threshold (0.0 - 1.0) at (`x`, `y`), repeating every 4 pixels
+pub fn bayer(x: u32, y: u32) -> f64 {
+    (BAYER_4[(y % 4) as usize][(x % 4) as usize] as f64 + 0.5) / 16.0
+}
+
+/// How 1-bit output spreads the difference between each cell and its pure black or white
+#[derive(Debug, Clone, Copy)]
+pub enum DitherAlgo {
+    /// Plain threshold
+    None,
+    Floyd,
+    Atkinson,
+    /// Ordered 4x4 Bayer pattern around the threshold
+    Bayer
+}
+
+impl FromStr for DitherAlgo {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(DitherAlgo::None),
+            "floyd" => Ok(DitherAlgo::Floyd),
+            "atkinson" => Ok(DitherAlgo::Atkinson),
+            "bayer" => Ok(DitherAlgo::Bayer),
+            _ => Err(format!("unknown dithering algorithm '{}', expected none, floyd, atkinson or bayer", s))
+        }
+    }
+}
+
+/// Reduces a `width` by `height` grid of luminances to on and off, one cell at a time
+/// in row by row order so error diffusion only ever pushes error forward.
+pub struct Ditherer {
+    algo: DitherAlgo,
+    threshold: f64,
+    width: usize,
+    height: usize,
+    /// Error diffused into each cell so far
+    error: Vec<f64>
+}
+
+impl Ditherer {
+    pub fn new(algo: DitherAlgo, threshold: u8, width: u32, height: u32) -> Self {
+        let (width, height) = (width as usize, height as usize);
+        Ditherer { algo, threshold: threshold as f64, width, height, error: vec![0.0; width * height] }
+    }
+
+    /// Whether the cell at (`x`, `y`) with luminance `l` (0.0 - 255.0) is on
+    pub fn quantize(&mut self, x: u32, y: u32, l: f64) -> bool {
+        match self.algo {
+            DitherAlgo::None => l >= self.threshold,
+            DitherAlgo::Bayer => l >= self.threshold + (bayer(x, y) - 0.5) * 255.0,
+            DitherAlgo::Floyd => self.diffuse(x, y, l, FLOYD_STEINBERG),
+            DitherAlgo::Atkinson => self.diffuse(x, y, l, ATKINSON)
+        }
+    }
+
+    fn diffuse(&mut self, x: u32, y: u32, l: f64, kernel: &[(i64, i64, f64)]) -> bool {
+        let (x, y) = (x as usize, y as usize);
+        let value = l + self.error[y * self.width + x];
+        let on = value >= self.threshold;
+        let error = value - if on { 255.0 } else { 0.0 };
+
+        for (dx, dy, share) in kernel {
+            let (nx, ny) = (x as i64 + dx, y as i64 + dy);
+            if nx >= 0 && (nx as usize) < self.width && (ny as usize) < self.height {
+                self.error[ny as usize * self.width + nx as usize] += error * share;
+            }
+        }
+
+        on
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Share of a `size` by `size` field of luminance `l` that `algo` turns on
+    fn coverage(algo: DitherAlgo, l: f64, size: u32) -> f64 {
+        let mut ditherer = Ditherer::new(algo, 128, size, size);
+        let mut on = 0;
+        for y in 0..size {
+            for x in 0..size {
+                if ditherer.quantize(x, y, l) {
+                    on += 1;
+                }
+            }
+        }
+        on as f64 / (size * size) as f64
+    }
+
+    #[test]
+    fn bayer_thresholds_are_spread_evenly() {
+        let mut thresholds = (0..4).flat_map(|y| (0..4).map(move |x| bayer(x, y))).collect::<Vec<f64>>();
+        thresholds.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+        for (i, threshold) in thresholds.iter().enumerate() {
+            assert!(*threshold > 0.0 && *threshold < 1.0);
+            assert!((threshold - (i as f64 + 0.5) / 16.0).abs() < 1e-9);
+        }
+        assert_eq!(bayer(1, 2), bayer(5, 6));
+    }
+
+    #[test]
+    fn half_gray_is_half_on() {
+        for algo in [DitherAlgo::Floyd, DitherAlgo::Atkinson, DitherAlgo::Bayer] {
+            let share = coverage(algo, 127.5, 32);
+            assert!((share - 0.5).abs() < 0.05, "{:?} turned on {}", algo, share);
+        }
+    }
+
+    #[test]
+    fn threshold_without_dithering() {
+        assert_eq!(coverage(DitherAlgo::None, 127.0, 8), 0.0);
+        assert_eq!(coverage(DitherAlgo::None, 128.0, 8), 1.0);
+    }
+
+    #[test]
+    fn error_diffusion_follows_tone() {
+        for algo in [DitherAlgo::Floyd, DitherAlgo::Atkinson] {
+            assert_eq!(coverage(algo, 0.0, 16), 0.0);
+            assert_eq!(coverage(algo, 255.0, 16), 1.0);
+        }
+
+        let quarter = coverage(DitherAlgo::Floyd, 64.0, 32);
+        assert!((quarter - 0.25).abs() < 0.05, "Floyd turned on {}", quarter);
+
+        // Atkinson drops part of the error, losing some of the tone away from mid gray
+        let quarter = coverage(DitherAlgo::Atkinson, 64.0, 32);
+        assert!(quarter > 0.1 && quarter <= 0.25, "Atkinson turned on {}", quarter);
+    }
+}
@@ -0,0 +1,156 @@
+//! Character encodings for output files and ANSI art
+
+use std::fmt;
+use std::io::{self, Write};
+use std::str::{self, FromStr};
+
+/// Byte used for characters the encoding can't represent
+pub const REPLACEMENT: u8 = b'?';
+
+/// Characters 0x80 - 0xFF of code page 437
+const CP437_HIGH: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å',
+    'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ',
+    'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»',
+    '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕', '╣', '║', '╗', '╝', '╜', '╛', '┐',
+    '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦', '╠', '═', '╬', '╧',
+    '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐', '▀',
+    'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩',
+    '≡', '±', '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{a0}',
+];
+
+/// Decode `bytes` as code page 437, keeping ASCII (and so escape sequences) as is
+pub fn decode_cp437(bytes: &[u8]) -> String {
+    bytes.iter().map(|&b| if b.is_ascii() { b as char } else { CP437_HIGH[(b - 0x80) as usize] }).collect()
+}
+
+/// Encoding of the output file
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputEncoding {
+    Utf8,
+    /// ISO-8859-1
+    Latin1,
+    /// The original IBM PC code page
+    Cp437
+}
+
+impl FromStr for OutputEncoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "utf8" | "utf-8" => Ok(OutputEncoding::Utf8),
+            "latin1" => Ok(OutputEncoding::Latin1),
+            "cp437" => Ok(OutputEncoding::Cp437),
+            _ => Err(format!("unknown encoding '{}', expected utf8, latin1 or cp437", s))
+        }
+    }
+}
+
+impl fmt::Display for OutputEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OutputEncoding::Utf8 => write!(f, "utf8"),
+            OutputEncoding::Latin1 => write!(f, "latin1"),
+            OutputEncoding::Cp437 => write!(f, "cp437")
+        }
+    }
+}
+
+impl OutputEncoding {
+    /// Whether `c` can be written without being replaced
+    pub fn can_encode(self, c: char) -> bool {
+        self == OutputEncoding::Utf8 || self.encode(c).is_some()
+    }
+
+    /// Single byte encoding of `c`, if this is a single byte encoding that can represent it
+    fn encode(self, c: char) -> Option<u8> {
+        match self {
+            OutputEncoding::Utf8 => None,
+            _ if c.is_ascii() => Some(c as u8),
+            OutputEncoding::Latin1 => {
+                if (c as u32) <= 0xFF { Some(c as u8) } else { None }
+            }
+            OutputEncoding::Cp437 => {
+                CP437_HIGH.iter().position(|high| *high == c).map(|i| 0x80 + i as u8)
+            }
+        }
+    }
+}
+
+/// Re-encodes UTF-8 written to it before passing it on to `W`
+pub struct Encoder<W: Write> {
+    inner: W,
+    encoding: OutputEncoding,
+    /// Trailing bytes of an incomplete UTF-8 sequence
+    pending: Vec<u8>
+}
+
+impl<W: Write> Encoder<W> {
+    pub fn new(inner: W, encoding: OutputEncoding) -> Self {
+        Encoder { inner, encoding, pending: Vec::new() }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.encoding == OutputEncoding::Utf8 {
+            return self.inner.write(buf);
+        }
+
+        self.pending.extend_from_slice(buf);
+        let valid = match str::from_utf8(&self.pending) {
+            Ok(s) => s.len(),
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e))
+        };
+
+        let encoded = str::from_utf8(&self.pending[..valid])
+            .unwrap()
+            .chars()
+            .map(|c| self.encoding.encode(c).unwrap_or(REPLACEMENT))
+            .collect::<Vec<u8>>();
+
+        self.inner.write_all(&encoded)?;
+        self.pending.drain(..valid);
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(encoding: OutputEncoding, text: &str) -> Vec<u8> {
+        let mut encoder = Encoder::new(Vec::new(), encoding);
+        // Split inside the multi-byte characters
+        for byte in text.as_bytes() {
+            encoder.write_all(&[*byte]).unwrap();
+        }
+        encoder.inner
+    }
+
+    #[test]
+    fn single_byte_encodings() {
+        assert_eq!(encode(OutputEncoding::Latin1, "aé░"), vec![b'a', 0xe9, REPLACEMENT]);
+        assert_eq!(encode(OutputEncoding::Cp437, "aé░"), vec![b'a', 0x82, 0xb0]);
+        assert_eq!(encode(OutputEncoding::Utf8, "é"), "é".as_bytes());
+    }
+
+    #[test]
+    fn decodes_cp437() {
+        assert_eq!(decode_cp437(b"\x1b[31m\xb0\xb1\xb2a\x82"), "\x1b[31m░▒▓aé");
+    }
+
+    #[test]
+    fn can_encode() {
+        assert!(OutputEncoding::Utf8.can_encode('⣿'));
+        assert!(!OutputEncoding::Latin1.can_encode('⣿'));
+        assert!(OutputEncoding::Cp437.can_encode('▓'));
+    }
+}
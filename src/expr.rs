@@ -0,0 +1,309 @@
+//! Small arithmetic expressions evaluated for each tile
+//!
+//! Supports numbers, the variables in `VARIABLES`, `+ - * / %`, comparisons,
+//! `&&`, `||`, `!`, `cond ? a : b`, parentheses and the functions
+//! `min`, `max`, `abs`, `floor`, `sqrt`, `sin` and `cos`.
+//! Comparisons and logical operators produce 1 for true and 0 for false.
+
+use std::error::Error;
+use std::fmt;
+
+/// Variables available to expressions
+pub const VARIABLES: [&str; 9] = ["r", "g", "b", "l", "x", "y", "w", "h", "n"];
+
+#[derive(Debug, Clone)]
+enum Node {
+    Number(f64),
+    Variable(usize),
+    Unary(char, Box<Node>),
+    Binary(&'static str, Box<Node>, Box<Node>),
+    Ternary(Box<Node>, Box<Node>, Box<Node>),
+    Call(&'static str, Vec<Node>)
+}
+
+/// A parsed expression
+#[derive(Debug, Clone)]
+pub struct Expr {
+    source: String,
+    root: Node
+}
+
+/// Error in an expression, pointing at where it went wrong
+pub struct ExprError {
+    source: String,
+    message: String
+}
+
+impl fmt::Display for ExprError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "in expression '{}': {}", self.source, self.message)
+    }
+}
+
+// Shown when returned from main
+impl fmt::Debug for ExprError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl Error for ExprError {}
+
+const BINARY: &[&[&str]] = &[
+    &["||"],
+    &["&&"],
+    &["==", "!="],
+    &["<=", ">=", "<", ">"],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+const FUNCTIONS: &[(&str, usize)] = &[
+    ("min", 2), ("max", 2), ("abs", 1), ("floor", 1), ("sqrt", 1), ("sin", 1), ("cos", 1),
+];
+
+struct Parser<'a> {
+    input: &'a str,
+    pos: usize
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, message: String) -> ExprError {
+        let position = self.input[..self.pos].chars().count() + 1;
+        ExprError { source: self.input.to_string(), message: format!("{} at position {}", message, position) }
+    }
+
+    /// Length in bytes of the characters matching `pred` at the current position
+    fn span(&self, pred: impl Fn(char) -> bool) -> usize {
+        self.input[self.pos..].chars().take_while(|c| pred(*c)).map(char::len_utf8).sum()
+    }
+
+    fn skip_whitespace(&mut self) {
+        self.pos += self.span(char::is_whitespace);
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_whitespace();
+        if self.input[self.pos..].starts_with(token) {
+            self.pos += token.len();
+            true
+        }
+        else {
+            false
+        }
+    }
+
+    fn expect(&mut self, token: &str) -> Result<(), ExprError> {
+        if self.eat(token) { Ok(()) } else { Err(self.error(format!("expected '{}'", token))) }
+    }
+
+    fn ternary(&mut self) -> Result<Node, ExprError> {
+        let cond = self.binary(0)?;
+        if self.eat("?") {
+            let then = self.ternary()?;
+            self.expect(":")?;
+            let otherwise = self.ternary()?;
+            return Ok(Node::Ternary(Box::new(cond), Box::new(then), Box::new(otherwise)));
+        }
+        Ok(cond)
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Node, ExprError> {
+        if level == BINARY.len() {
+            return self.unary();
+        }
+
+        let mut lhs = self.binary(level + 1)?;
+        'outer: loop {
+            for op in BINARY[level] {
+                if self.eat(op) {
+                    let rhs = self.binary(level + 1)?;
+                    lhs = Node::Binary(op, Box::new(lhs), Box::new(rhs));
+                    continue 'outer;
+                }
+            }
+            return Ok(lhs);
+        }
+    }
+
+    fn unary(&mut self) -> Result<Node, ExprError> {
+        if self.eat("-") {
+            return Ok(Node::Unary('-', Box::new(self.unary()?)));
+        }
+        if self.input[self.pos..].starts_with('!') && !self.input[self.pos..].starts_with("!=") && self.eat("!") {
+            return Ok(Node::Unary('!', Box::new(self.unary()?)));
+        }
+        self.atom()
+    }
+
+    fn atom(&mut self) -> Result<Node, ExprError> {
+        self.skip_whitespace();
+        let rest = &self.input[self.pos..];
+
+        if self.eat("(") {
+            let node = self.ternary()?;
+            self.expect(")")?;
+            return Ok(node);
+        }
+
+        let number = self.span(|c| c.is_ascii_digit() || c == '.');
+        if number > 0 {
+            let value = rest[..number].parse::<f64>().map_err(|_| self.error(format!("invalid number '{}'", &rest[..number])))?;
+            self.pos += number;
+            return Ok(Node::Number(value));
+        }
+
+        let name_len = self.span(|c| c.is_ascii_alphanumeric() || c == '_');
+        if name_len == 0 {
+            return Err(self.error("expected a value".to_string()));
+        }
+        let name = &rest[..name_len];
+
+        if let Some((function, arity)) = FUNCTIONS.iter().find(|(function, _)| *function == name) {
+            self.pos += name_len;
+            self.expect("(")?;
+            let mut args = vec![self.ternary()?];
+            while self.eat(",") {
+                args.push(self.ternary()?);
+            }
+            self.expect(")")?;
+            if args.len() != *arity {
+                return Err(self.error(format!("{} takes {} argument(s)", function, arity)));
+            }
+            return Ok(Node::Call(function, args));
+        }
+
+        match VARIABLES.iter().position(|variable| *variable == name) {
+            Some(i) => {
+                self.pos += name_len;
+                Ok(Node::Variable(i))
+            }
+            None => Err(self.error(format!("unknown variable '{}', expected one of {}", name, VARIABLES.join(", "))))
+        }
+    }
+}
+
+fn truth(value: bool) -> f64 {
+    if value { 1.0 } else { 0.0 }
+}
+
+impl Node {
+    fn eval(&self, vars: &[f64]) -> f64 {
+        match self {
+            Node::Number(n) => *n,
+            Node::Variable(i) => vars[*i],
+            Node::Unary('-', node) => -node.eval(vars),
+            Node::Unary(_, node) => truth(node.eval(vars) == 0.0),
+            Node::Binary(op, lhs, rhs) => {
+                let (a, b) = (lhs.eval(vars), rhs.eval(vars));
+                match *op {
+                    "||" => truth(a != 0.0 || b != 0.0),
+                    "&&" => truth(a != 0.0 && b != 0.0),
+                    "==" => truth(a == b),
+                    "!=" => truth(a != b),
+                    "<=" => truth(a <= b),
+                    ">=" => truth(a >= b),
+                    "<" => truth(a < b),
+                    ">" => truth(a > b),
+                    "+" => a + b,
+                    "-" => a - b,
+                    "*" => a * b,
+                    "/" => a / b,
+                    _ => a % b
+                }
+            }
+            Node::Ternary(cond, then, otherwise) => {
+                if cond.eval(vars) != 0.0 { then.eval(vars) } else { otherwise.eval(vars) }
+            }
+            Node::Call(function, args) => {
+                let a = args[0].eval(vars);
+                match *function {
+                    "min" => a.min(args[1].eval(vars)),
+                    "max" => a.max(args[1].eval(vars)),
+                    "abs" => a.abs(),
+                    "floor" => a.floor(),
+                    "sqrt" => a.sqrt(),
+                    "sin" => a.sin(),
+                    _ => a.cos()
+                }
+            }
+        }
+    }
+}
+
+impl Expr {
+    pub fn parse(source: &str) -> Result<Expr, ExprError> {
+        let mut parser = Parser { input: source, pos: 0 };
+        let root = parser.ternary()?;
+
+        parser.skip_whitespace();
+        if parser.pos < source.len() {
+            return Err(parser.error("unexpected input".to_string()));
+        }
+
+        Ok(Expr { source: source.to_string(), root })
+    }
+
+    /// Evaluate with the values of each of `VARIABLES`, in order
+    pub fn eval(&self, vars: &[f64; VARIABLES.len()]) -> Result<f64, ExprError> {
+        let value = self.root.eval(vars);
+
+        if !value.is_finite() {
+            let at = VARIABLES.iter().zip(vars.iter())
+                .map(|(name, value)| format!("{}={}", name, value))
+                .collect::<Vec<String>>()
+                .join(" ");
+            return Err(ExprError { source: self.source.clone(), message: format!("produced {} with {}", value, at) });
+        }
+
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(source: &str) -> f64 {
+        Expr::parse(source).unwrap().eval(&[10.0, 20.0, 30.0, 128.0, 1.0, 2.0, 80.0, 40.0, 10.0]).unwrap()
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(eval("1 + 2 * 3"), 7.0);
+        assert_eq!(eval("10 - 4 - 3"), 3.0);
+        assert_eq!(eval("1 + 1 == 2 && 3 > 2"), 1.0);
+        assert_eq!(eval("0 || 1 ? 5 : 6"), 5.0);
+    }
+
+    #[test]
+    fn unary_and_parentheses() {
+        assert_eq!(eval("-2 * 3"), -6.0);
+        assert_eq!(eval("-(2 + 3)"), -5.0);
+        assert_eq!(eval("(1 + 2) * 3"), 9.0);
+        assert_eq!(eval("!0 + !5"), 1.0);
+        assert_eq!(eval("1 != 2"), 1.0);
+    }
+
+    #[test]
+    fn variables_and_functions() {
+        assert_eq!(eval("r + g + b"), 60.0);
+        assert_eq!(eval("max(x, y) + min(w, h)"), 42.0);
+        assert_eq!(eval("floor(l / n)"), 12.0);
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        for source in ["", "1 +", "(1", "foo", "max(1)", "1 2", "1..2"] {
+            assert!(Expr::parse(source).is_err(), "{} should not parse", source);
+        }
+        assert!(Expr::parse("1 / 0").unwrap().eval(&[0.0; 9]).is_err());
+    }
+
+    #[test]
+    fn unicode_whitespace() {
+        assert_eq!(eval("1\u{a0}+\u{2003}2"), 3.0);
+        let err = Expr::parse("\u{a0}\u{a0}x y").unwrap_err().to_string();
+        assert!(err.contains("position 5"), "{}", err);
+    }
+}
@@ -0,0 +1,97 @@
+//! Luminance to color gradients
+
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+use crate::palette::Rgb;
+
+/// Colors at increasing luminances, blended linearly in between
+#[derive(Debug, Clone)]
+pub struct Gradient {
+    stops: Vec<(u8, Rgb)>
+}
+
+impl Gradient {
+    /// Read whitespace separated `luminance:#rrggbb` stops from `path`
+    pub fn read(path: &Path) -> Result<Gradient, Box<dyn Error>> {
+        let contents = fs::read_to_string(path)?;
+        let mut stops = Vec::new();
+
+        for stop in contents.split_whitespace() {
+            let invalid = || format!("invalid gradient stop '{}' in {}, expected luminance:#rrggbb", stop, path.display());
+            let (l, color) = stop.split_once(':').ok_or_else(invalid)?;
+            let l = l.parse::<u8>().map_err(|_| invalid())?;
+            let color = crate::parse_color(color).map_err(|_| invalid())?;
+
+            if stops.iter().any(|(existing, _)| *existing == l) {
+                return Err(format!("luminance {} has more than one stop in {}", l, path.display()).into());
+            }
+            stops.push((l, color));
+        }
+
+        if stops.is_empty() {
+            return Err(format!("{} contains no gradient stops", path.display()).into());
+        }
+        stops.sort_by_key(|(l, _)| *l);
+
+        Ok(Gradient { stops })
+    }
+
+    /// Color at luminance `l` (0.0 - 255.0), clamped to the first and last stops
+    pub fn at(&self, l: f64) -> Rgb {
+        let first = self.stops[0];
+        let last = self.stops[self.stops.len() - 1];
+        if l <= first.0 as f64 {
+            return first.1;
+        }
+        if l >= last.0 as f64 {
+            return last.1;
+        }
+
+        let upper = self.stops.iter().position(|(stop, _)| *stop as f64 >= l).unwrap();
+        let ((l0, c0), (l1, c1)) = (self.stops[upper - 1], self.stops[upper]);
+        let t = (l - l0 as f64) / (l1 - l0) as f64;
+        let blend = |a: u8, b: u8| (a as f64 + (b as f64 - a as f64) * t).round() as u8;
+
+        (blend(c0.0, c1.0), blend(c0.1, c1.1), blend(c0.2, c1.2))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_str(name: &str, contents: &str) -> Result<Gradient, Box<dyn Error>> {
+        let path = std::env::temp_dir().join(format!("rascii-gradient-{}-{}.txt", std::process::id(), name));
+        fs::write(&path, contents).unwrap();
+        let gradient = Gradient::read(&path);
+        fs::remove_file(&path).unwrap();
+        gradient
+    }
+
+    #[test]
+    fn interpolates_between_sorted_stops() {
+        let gradient = read_str("blend", "255:#ffffff 0:#000000\n128:#ff0000").unwrap();
+        assert_eq!(gradient.at(0.0), (0, 0, 0));
+        assert_eq!(gradient.at(64.0), (128, 0, 0));
+        assert_eq!(gradient.at(128.0), (255, 0, 0));
+        assert_eq!(gradient.at(191.5), (255, 128, 128));
+    }
+
+    #[test]
+    fn clamps_past_the_ends() {
+        let gradient = read_str("clamp", "50:#102030 200:#405060").unwrap();
+        assert_eq!(gradient.at(0.0), (16, 32, 48));
+        assert_eq!(gradient.at(255.0), (64, 80, 96));
+    }
+
+    #[test]
+    fn rejects_bad_stops() {
+        assert!(read_str("duplicate", "10:#000000 10:#ffffff").is_err());
+        assert!(read_str("range", "256:#000000").is_err());
+        assert!(read_str("color", "10:#00000g").is_err());
+        assert!(read_str("separator", "10#000000").is_err());
+        assert!(read_str("empty", "\n").is_err());
+    }
+}
@@ -0,0 +1,81 @@
+//! Minimal JSON output
+
+use std::io::{self, Write};
+
+/// A JSON value
+pub enum Json {
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    /// Fields in the order they're written
+    Object(Vec<(&'static str, Json)>)
+}
+
+impl Json {
+    /// Write the value to `out`, indented with two spaces unless `compact`
+    pub fn write<W: Write>(&self, out: &mut W, compact: bool) -> io::Result<()> {
+        self.write_indented(out, compact, 0)?;
+        if !compact {
+            writeln!(out)?;
+        }
+        Ok(())
+    }
+
+    fn write_indented<W: Write>(&self, out: &mut W, compact: bool, depth: usize) -> io::Result<()> {
+        let newline = |out: &mut W, depth: usize| -> io::Result<()> {
+            if !compact {
+                write!(out, "\n{:1$}", "", depth * 2)?;
+            }
+            Ok(())
+        };
+
+        match self {
+            Json::Number(n) => write!(out, "{}", n),
+            Json::String(s) => write_string(out, s),
+            Json::Array(values) if values.is_empty() => write!(out, "[]"),
+            Json::Array(values) => {
+                write!(out, "[")?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        write!(out, ",")?;
+                    }
+                    newline(out, depth + 1)?;
+                    value.write_indented(out, compact, depth + 1)?;
+                }
+                newline(out, depth)?;
+                write!(out, "]")
+            }
+            Json::Object(fields) if fields.is_empty() => write!(out, "{{}}"),
+            Json::Object(fields) => {
+                write!(out, "{{")?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(out, ",")?;
+                    }
+                    newline(out, depth + 1)?;
+                    write_string(out, key)?;
+                    write!(out, "{}", if compact { ":" } else { ": " })?;
+                    value.write_indented(out, compact, depth + 1)?;
+                }
+                newline(out, depth)?;
+                write!(out, "}}")
+            }
+        }
+    }
+}
+
+fn write_string<W: Write>(out: &mut W, s: &str) -> io::Result<()> {
+    write!(out, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(out, "\\\"")?,
+            '\\' => write!(out, "\\\\")?,
+            '\n' => write!(out, "\\n")?,
+            '\r' => write!(out, "\\r")?,
+            '\t' => write!(out, "\\t")?,
+            c if c.is_control() => write!(out, "\\u{:04x}", c as u32)?,
+            c => write!(out, "{}", c)?
+        }
+    }
+    write!(out, "\"")
+}
@@ -1,14 +1,50 @@
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
 use std::path::{PathBuf, Path};
 use structopt::StructOpt;
-use image::{DynamicImage, RgbImage};
+use image::{DynamicImage, GenericImageView, GrayImage, ImageBuffer, ImageDecoder, ImageFormat, ImageResult, Luma, RgbImage};
+use image::imageops::{self, FilterType};
 use std::error::Error;
-use std::io::Write;
-use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
+use std::str::FromStr;
+use std::io::{self, Cursor, IsTerminal, Read, Write};
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+use termcolor::{Ansi, Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
+
+mod ansi;
+mod braille;
+mod dither;
+mod encoding;
+mod expr;
+mod gradient;
+mod json;
+mod palette;
+mod ramp;
+mod shape;
+
+use encoding::{Encoder, OutputEncoding};
+use dither::{DitherAlgo, Ditherer};
+use expr::Expr;
+use gradient::Gradient;
+use json::Json;
+use palette::ColorDistance;
 
 /// 10 Levels of grayscale
 const GSCALE_10: &[char] = &[' ','.',':','-','=','+','*','#','%','@'];
 const GSCALE_70: &str = " .\"`^\",:;Il!i~+_-?][}{1)(|\\/tfjrxnuvczXYUJCLQ0OZmwqpdbkhao*#MW&8%B@$";
 const GAMMA: f64 = 2.2;
+/// Separates renders appended to the same file
+const RENDER_SEPARATOR: &[u8] = b"\x0c\n";
+
+/// End of the progress printed ahead of a render on stdout
+const PROGRESS_END: &str = "   Done!\n\n";
+
+/// Time each frame of --color-cycle is shown
+const CYCLE_FRAME_DELAY: Duration = Duration::from_millis(50);
+
+/// Narrowest line --max-line can cap the output to before warning it may be unrecognizable
+const MIN_LEGIBLE_WIDTH: u32 = 16;
 
 const LOGO: &str = r"
                     _ _
@@ -21,30 +57,110 @@ by Avery Wagar (@ajmwagar)
 
 type RasciiOutput = Vec<Vec<(char, RasciiColor)>>;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
+#[allow(clippy::upper_case_acronyms)]
 enum RasciiColor {
     RGB(u8, u8, u8),
     Grayscale(u8)
 }
 
-impl RasciiColor {
-    fn to_grayscale(&self) -> u8 {
-        /*
-         * Rlin = R^GAMMA,  Glin = G^GAMMA,  Blin = B^GAMMA
-         * Y = .2126 * R^GAMMA + .7152 * G^GAMMA + .0722 * B^GAMMA
-         * L* = 116 * Y ^ 1/3 - 16
-         */
+/// Format of the converted output
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum OutputFormat {
+    /// Characters colored with ANSI escape codes
+    Ansi,
+    /// Characters and their colors as JSON
+    Json,
+    /// One line per character with its position, color and luminance
+    Csv
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ansi" => Ok(OutputFormat::Ansi),
+            "json" => Ok(OutputFormat::Json),
+            "csv" => Ok(OutputFormat::Csv),
+            _ => Err(format!("unknown output format '{}', expected ansi, json or csv", s))
+        }
+    }
+}
+
+/// Layout of the tiles sampled for each character
+#[derive(Debug, Clone, Copy)]
+enum TileShape {
+    /// A regular grid
+    Rect,
+    /// Alternate rows offset by half a tile, approximating a hexagonal grid
+    Hex
+}
+
+impl FromStr for TileShape {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rect" => Ok(TileShape::Rect),
+            "hex" => Ok(TileShape::Hex),
+            _ => Err(format!("unknown tile shape '{}', expected rect or hex", s))
+        }
+    }
+}
+
+/// Method of converting colors to grayscale
+#[derive(Debug, Clone, Copy)]
+enum GrayscaleMethod {
+    /// Perceptual luminance (CIE L*)
+    Luminance,
+    /// HSL lightness, (max + min) / 2
+    Lightness,
+    /// Average of the channels
+    Average,
+    /// HSV value, the brightest channel
+    Value
+}
+
+impl FromStr for GrayscaleMethod {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "luminance" => Ok(GrayscaleMethod::Luminance),
+            "lightness" => Ok(GrayscaleMethod::Lightness),
+            "average" => Ok(GrayscaleMethod::Average),
+            "value" => Ok(GrayscaleMethod::Value),
+            _ => Err(format!("unknown grayscale method '{}', expected luminance, lightness, average or value", s))
+        }
+    }
+}
 
+impl RasciiColor {
+    fn to_grayscale(&self, method: GrayscaleMethod) -> u8 {
         match self {
             RasciiColor::RGB(r,g,b) => {
-                let rlin = (*r as f64).powf(GAMMA);
-                let blin = (*b as f64).powf(GAMMA);
-                let glin = (*g as f64).powf(GAMMA);
+                let (r, g, b) = (*r as u32, *g as u32, *b as u32);
+                match method {
+                    GrayscaleMethod::Luminance => {
+                        /*
+                         * Rlin = R^GAMMA,  Glin = G^GAMMA,  Blin = B^GAMMA
+                         * Y = .2126 * R^GAMMA + .7152 * G^GAMMA + .0722 * B^GAMMA
+                         * L* = 116 * Y ^ 1/3 - 16
+                         */
+                        let rlin = (r as f64 / 255.0).powf(GAMMA);
+                        let glin = (g as f64 / 255.0).powf(GAMMA);
+                        let blin = (b as f64 / 255.0).powf(GAMMA);
 
-                let y = (0.2126 * rlin) + (0.7152 * glin) + (0.0722 * blin);
+                        let y = (0.2126 * rlin) + (0.7152 * glin) + (0.0722 * blin);
 
-                let l = (116.0 * y.powf(1.0 / 3.0) - 16.0) as u8;
-                l
+                        // L* is 0 - 100
+                        ((116.0 * y.powf(1.0 / 3.0) - 16.0).max(0.0) * 2.55) as u8
+                    }
+                    GrayscaleMethod::Lightness => ((r.max(g).max(b) + r.min(g).min(b)) / 2) as u8,
+                    GrayscaleMethod::Average => ((r + g + b) / 3) as u8,
+                    GrayscaleMethod::Value => r.max(g).max(b) as u8
+                }
             }
             RasciiColor::Grayscale(l) => {
                 *l
@@ -52,6 +168,187 @@ impl RasciiColor {
         }
 
     }
+
+    /// Scale the color towards black by `factor` (0.0 - 1.0)
+    fn scale(&self, factor: f64) -> RasciiColor {
+        match self {
+            RasciiColor::RGB(r,g,b) => RasciiColor::RGB(
+                (*r as f64 * factor) as u8,
+                (*g as f64 * factor) as u8,
+                (*b as f64 * factor) as u8
+            ),
+            RasciiColor::Grayscale(l) => RasciiColor::Grayscale((*l as f64 * factor) as u8)
+        }
+    }
+
+    /// Rotate the hue of the color by `degrees`, keeping its luminance
+    fn rotate_hue(&self, degrees: f64) -> RasciiColor {
+        let (r, g, b) = match self {
+            RasciiColor::RGB(r,g,b) => (*r as f64, *g as f64, *b as f64),
+            RasciiColor::Grayscale(_) => return self.clone()
+        };
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        let channel = |v: f64| v.round().clamp(0.0, 255.0) as u8;
+
+        RasciiColor::RGB(
+            channel((0.213 + 0.787 * cos - 0.213 * sin) * r + (0.715 - 0.715 * cos - 0.715 * sin) * g + (0.072 - 0.072 * cos + 0.928 * sin) * b),
+            channel((0.213 - 0.213 * cos + 0.143 * sin) * r + (0.715 + 0.285 * cos + 0.140 * sin) * g + (0.072 - 0.072 * cos - 0.283 * sin) * b),
+            channel((0.213 - 0.213 * cos - 0.787 * sin) * r + (0.715 - 0.715 * cos + 0.715 * sin) * g + (0.072 + 0.928 * cos + 0.072 * sin) * b)
+        )
+    }
+
+    fn to_rgb(&self) -> (u8, u8, u8) {
+        match self {
+            RasciiColor::RGB(r,g,b) => (*r, *g, *b),
+            RasciiColor::Grayscale(l) => (*l, *l, *l)
+        }
+    }
+}
+
+/// Parse an image format name as accepted on the command line
+fn parse_format(name: &str) -> Result<ImageFormat, String> {
+    match name.to_lowercase().as_str() {
+        "png" => Ok(ImageFormat::PNG),
+        "jpeg" | "jpg" => Ok(ImageFormat::JPEG),
+        "gif" => Ok(ImageFormat::GIF),
+        "webp" => Ok(ImageFormat::WEBP),
+        "pnm" | "pbm" | "pgm" | "ppm" => Ok(ImageFormat::PNM),
+        "tiff" | "tif" => Ok(ImageFormat::TIFF),
+        "tga" => Ok(ImageFormat::TGA),
+        "bmp" => Ok(ImageFormat::BMP),
+        "ico" => Ok(ImageFormat::ICO),
+        "hdr" => Ok(ImageFormat::HDR),
+        _ => Err(format!("unknown image format '{}'", name))
+    }
+}
+
+/// Parse a `#rrggbb` hex color
+fn parse_color(color: &str) -> Result<(u8, u8, u8), String> {
+    let hex = color.trim_start_matches('#');
+    let channel = |i: usize| hex.get(i..i + 2).and_then(|c| u8::from_str_radix(c, 16).ok());
+
+    match (hex.len(), channel(0), channel(2), channel(4)) {
+        (6, Some(r), Some(g), Some(b)) => Ok((r, g, b)),
+        _ => Err(format!("invalid color '{}', expected #rrggbb", color))
+    }
+}
+
+/// Parse a strength that can't be negative
+fn parse_strength(strength: &str) -> Result<f64, String> {
+    match strength.trim().parse::<f64>() {
+        Ok(n) if n.is_finite() && n >= 0.0 => Ok(n),
+        _ => Err(format!("invalid strength '{}', expected a number of 0 or more", strength))
+    }
+}
+
+/// Parse a share from 0 to 1
+fn parse_fraction(fraction: &str) -> Result<f64, String> {
+    match fraction.trim().parse::<f64>() {
+        Ok(n) if (0.0..=1.0).contains(&n) => Ok(n),
+        _ => Err(format!("invalid value '{}', expected a number from 0 to 1", fraction))
+    }
+}
+
+/// Parse a `width:height` aspect ratio
+fn parse_ratio(ratio: &str) -> Result<f64, String> {
+    let parts = ratio
+        .split(':')
+        .map(|part| part.trim().parse::<f64>())
+        .collect::<Result<Vec<f64>, _>>()
+        .map_err(|_| format!("invalid aspect ratio '{}', expected width:height", ratio))?;
+
+    match parts.as_slice() {
+        [w, h] if *w > 0.0 && *h > 0.0 && w.is_finite() && h.is_finite() => Ok(w / h),
+        _ => Err(format!("invalid aspect ratio '{}', expected width:height", ratio))
+    }
+}
+
+/// Parse a `x,y,width,height` rectangle in source pixels
+fn parse_region(region: &str) -> Result<(u32, u32, u32, u32), String> {
+    let parts = region
+        .split(',')
+        .map(|part| part.trim().parse::<u32>())
+        .collect::<Result<Vec<u32>, _>>()
+        .map_err(|_| format!("invalid region '{}', expected x,y,width,height", region))?;
+
+    match parts.as_slice() {
+        [x, y, w, h] if *w > 0 && *h > 0 => Ok((*x, *y, *w, *h)),
+        _ => Err(format!("invalid region '{}', expected x,y,width,height", region))
+    }
+}
+
+/// Parse a `widthxheight` size
+fn parse_size(size: &str) -> Result<(u32, u32), String> {
+    let invalid = || format!("invalid size '{}', expected widthxheight", size);
+    let (w, h) = size.split_once('x').ok_or_else(invalid)?;
+
+    match (w.trim().parse::<u32>(), h.trim().parse::<u32>()) {
+        (Ok(0), Ok(_)) | (Ok(_), Ok(0)) => Err(format!("size '{}' has no room for any characters", size)),
+        (Ok(w), Ok(h)) => Ok((w, h)),
+        _ => Err(invalid())
+    }
+}
+
+/// Corner of the output
+#[derive(Debug, Clone, Copy)]
+enum Corner {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight
+}
+
+impl FromStr for Corner {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "top-left" => Ok(Corner::TopLeft),
+            "top-right" => Ok(Corner::TopRight),
+            "bottom-left" => Ok(Corner::BottomLeft),
+            "bottom-right" => Ok(Corner::BottomRight),
+            _ => Err(format!("unknown corner '{}', expected top-left, top-right, bottom-left or bottom-right", s))
+        }
+    }
+}
+
+/// Read the dimensions of an image from its header without decoding it
+fn header_dimensions(bytes: &[u8], format: ImageFormat) -> Option<(u64, u64)> {
+    fn dimensions<D: ImageDecoder>(decoder: ImageResult<D>) -> Option<(u64, u64)> {
+        decoder.ok().map(|decoder| decoder.dimensions())
+    }
+
+    let r = Cursor::new(bytes);
+    match format {
+        ImageFormat::PNG => dimensions(image::png::PNGDecoder::new(r)),
+        ImageFormat::JPEG => dimensions(image::jpeg::JPEGDecoder::new(r)),
+        ImageFormat::GIF => dimensions(image::gif::Decoder::new(r)),
+        ImageFormat::WEBP => dimensions(image::webp::WebpDecoder::new(r)),
+        ImageFormat::PNM => dimensions(image::pnm::PNMDecoder::new(r)),
+        ImageFormat::TIFF => dimensions(image::tiff::TIFFDecoder::new(r)),
+        ImageFormat::TGA => dimensions(image::tga::TGADecoder::new(r)),
+        ImageFormat::BMP => dimensions(image::bmp::BMPDecoder::new(r)),
+        ImageFormat::ICO => dimensions(image::ico::ICODecoder::new(r)),
+        ImageFormat::HDR => dimensions(image::hdr::HDRAdapter::new(r))
+    }
+}
+
+/// Format of an image from its contents, or failing that the extension of `path`
+fn detect_format(bytes: &[u8], path: &Path) -> Option<ImageFormat> {
+    image::guess_format(bytes).ok().or_else(|| {
+        path.extension().and_then(|ext| ext.to_str()).and_then(|ext| parse_format(ext).ok())
+    })
+}
+
+/// Fail if the header of `bytes` declares more than `max_pixels` pixels
+fn check_size(bytes: &[u8], format: ImageFormat, path: &Path, max_pixels: u64) -> Result<(), Box<dyn Error>> {
+    match header_dimensions(bytes, format) {
+        Some((w, h)) if w.saturating_mul(h) > max_pixels => {
+            Err(format!("{} is {}x{}, more than the {} pixels allowed by --max-input-pixels",
+                path.display(), w, h, max_pixels).into())
+        }
+        _ => Ok(())
+    }
 }
 
 /// Image to ASCII converter
@@ -66,6 +363,10 @@ struct Opt {
     #[structopt(short = "b", long = "braille")]
     braille: bool,
 
+    /// Dither braille dots instead of thresholding them, for smoother gradients
+    #[structopt(long = "antialiased-braille", raw(requires = "\"braille\""))]
+    antialiased_braille: bool,
+
     #[structopt(short = "w", long = "width", default_value = "80")]
     /// Width in characters of the output
     width: u32,
@@ -78,177 +379,1442 @@ struct Opt {
     /// Height in characters of the output
     height: Option<u32>,
 
+    #[structopt(long = "glyph-blacklist")]
+    /// Characters to leave out of the ramp
+    glyph_blacklist: Option<String>,
+
+    #[structopt(long = "glyph-weights", parse(from_os_str))]
+    /// File of `char weight` lines measuring how much of a cell each character covers, used to order the ramp
+    glyph_weights: Option<PathBuf>,
+
+    #[structopt(long = "expr", parse(try_from_str = "Expr::parse"))]
+    /// Expression picking the index into the ramp of each character. Variables: r, g, b (average color),
+    /// l (luminance), x, y (position), w, h (output size) and n (ramp length). (e.g. "l > 128 ? n - 1 : 0")
+    expr: Option<Expr>,
+
+    #[structopt(long = "tile-shape", default_value = "rect")]
+    /// Layout of the sampled tiles. (rect, hex)
+    tile_shape: TileShape,
+
+    #[structopt(long = "grayscale-method", default_value = "luminance")]
+    /// How to convert colors to grayscale. (luminance, lightness, average, value)
+    grayscale_method: GrayscaleMethod,
+
+    #[structopt(long = "min-luminance", default_value = "0")]
+    /// Darkest luminance to map characters from. (0 - 255)
+    min_luminance: u8,
+
+    #[structopt(long = "max-luminance", default_value = "255")]
+    /// Brightest luminance to map characters from. (0 - 255)
+    max_luminance: u8,
+
+    #[structopt(long = "fit-to-file", parse(from_os_str))]
+    /// Match the width and height of an existing render, overriding --width and --height.
+    /// (Best written with -o or -q, though the logo and progress of plain stdout are skipped)
+    fit_to_file: Option<PathBuf>,
+
     #[structopt(long = "bg")]
     /// Enable coloring of background chars
     bg: bool,
 
-    /// Path of image file to convert
+    #[structopt(long = "fit-within", parse(try_from_str = "parse_size"), raw(conflicts_with_all = "&[\"height\", \"fit_to_file\"]"))]
+    /// Largest output keeping the image's aspect ratio that fits in a box of this many characters,
+    /// overriding --width. (e.g. 120x40)
+    fit_within: Option<(u32, u32)>,
+
+    #[structopt(long = "force-square-pixels", raw(conflicts_with_all = "&[\"height\", \"fit_to_file\", \"fit_within\", \"max_line\"]"))]
+    /// Map each source pixel to exactly one character, overriding --width. (For pixel art)
+    force_square_pixels: bool,
+
+    #[structopt(long = "max-line")]
+    /// Narrow the output so no line is longer than this many characters, for pasting into chat apps
+    max_line: Option<u32>,
+
+    #[structopt(long = "aspect-lock")]
+    /// Keep the image's aspect ratio when both width and height are given, letterboxing the rest
+    aspect_lock: bool,
+
+    #[structopt(long = "letterbox-char", default_value = " ")]
+    /// Character used for letterbox bars
+    letterbox_char: char,
+
+    #[structopt(long = "letterbox-color", parse(try_from_str = "parse_color"))]
+    /// Color of letterbox bars. (#rrggbb)
+    letterbox_color: Option<(u8, u8, u8)>,
+
+    #[structopt(long = "chunk-output")]
+    /// Flush output row by row instead of all at once
+    chunk_output: bool,
+
+    #[structopt(long = "chunk-delay", default_value = "0")]
+    /// Delay in milliseconds between rows when using --chunk-output
+    chunk_delay: u64,
+
+    #[structopt(long = "palette", parse(from_os_str), raw(requires = "\"color\""))]
+    /// Snap output colors to the nearest color of a GIMP palette (.gpl) file
+    palette: Option<PathBuf>,
+
+    #[structopt(long = "color-distance", raw(requires = "\"color\""))]
+    /// How to find the nearest palette color. (rgb, weighted-rgb, lab) [default: weighted-rgb]
+    color_distance: Option<ColorDistance>,
+
+    #[structopt(long = "color-passthrough", raw(requires = "\"color\""))]
+    /// Color each character with its exact source pixel when the output is as large as the image,
+    /// rather than averaging. (For pixel art)
+    color_passthrough: bool,
+
+    #[structopt(long = "monochrome-bits")]
+    /// Draw only blanks and the brightest character, for 1-bit output
+    monochrome_bits: bool,
+
+    #[structopt(long = "dither-algo", default_value = "none")]
+    /// How --monochrome-bits dithers the image. (none, floyd, atkinson, bayer)
+    dither_algo: DitherAlgo,
+
+    #[structopt(long = "threshold", default_value = "128")]
+    /// Luminance at which --monochrome-bits switches from blank to the brightest character
+    threshold: u8,
+
+    #[structopt(long = "alpha-dither")]
+    /// Blank out a share of the cells of semi-transparent areas in an ordered pattern,
+    /// rather than drawing them as if they were opaque
+    alpha_dither: bool,
+
+    #[structopt(long = "gradient-map", parse(from_os_str), raw(requires = "\"color\""))]
+    /// File of `luminance:#rrggbb` stops, coloring each character by its luminance along the gradient
+    /// instead of the image colors. (e.g. 0:#000080 128:#ff8800 255:#ffffff)
+    gradient_map: Option<PathBuf>,
+
+    #[structopt(long = "palette-lock-background", parse(try_from_str = "parse_color"), raw(requires = "\"palette\""))]
+    /// Reserve a palette color (#rrggbb) for blank characters, never using it for others
+    palette_lock_background: Option<(u8, u8, u8)>,
+
+    #[structopt(long = "palette-output", parse(from_os_str))]
+    /// Write the colors used by the output to a GIMP palette (.gpl) file
+    palette_output: Option<PathBuf>,
+
+    #[structopt(long = "fade-edges", default_value = "0", parse(try_from_str = "parse_strength"))]
+    /// Fade cells towards the background the further they are from the center. (0 disables)
+    fade_edges: f64,
+
+    #[structopt(long = "max-input-pixels", default_value = "268435456")]
+    /// Refuse to decode images with more pixels than this, checked from the image header
+    max_input_pixels: u64,
+
+    #[structopt(long = "retry-decode-as", parse(try_from_str = "parse_format"), raw(use_delimiter = "true", require_delimiter = "true"))]
+    /// Formats to retry decoding as, in order, if the image fails to open. (e.g. png,jpeg,bmp)
+    retry_decode_as: Vec<ImageFormat>,
+
+    #[structopt(long = "stdin-format", parse(try_from_str = "parse_format"))]
+    /// Format of an image read from stdin, instead of guessing it from the first bytes
+    stdin_format: Option<ImageFormat>,
+
+    #[structopt(short = "v", long = "verbose")]
+    /// Print extra information while converting
+    verbose: bool,
+
+    #[structopt(long = "strict")]
+    /// Fail instead of adjusting invalid or out of range options
+    strict: bool,
+
+    #[structopt(short = "q", long = "quiet")]
+    /// Only print the converted image. (Implied for json and csv written to stdout)
+    quiet: bool,
+
+    #[structopt(long = "antialias-chars")]
+    /// Pick characters by matching their shape to the detail in each cell. (slower)
+    antialias_chars: bool,
+
+    #[structopt(long = "color-from", parse(from_os_str))]
+    /// Take colors from another image, resized to match with a triangle filter, keeping characters from IMAGE
+    color_from: Option<PathBuf>,
+
+    #[structopt(long = "adaptive-contrast", default_value = "0", parse(try_from_str = "parse_fraction"))]
+    /// Map characters relative to the brightness of the surrounding area, keeping detail in both bright
+    /// and dark regions. (0 disables, 1 is fully local)
+    adaptive_contrast: f64,
+
+    #[structopt(long = "downscale-gamma-correct")]
+    /// Average tiles in linear light, so fine bright and dark detail keeps its overall brightness.
+    /// (The default, --no-downscale-gamma-correct overrides it)
+    downscale_gamma_correct: bool,
+
+    #[structopt(long = "no-downscale-gamma-correct")]
+    /// Average tiles directly on the pixel values like older versions, rather than in linear light.
+    /// Independent of the gamma used by --grayscale-method
+    no_downscale_gamma_correct: bool,
+
+    #[structopt(long = "depth-map", parse(from_os_str))]
+    /// Depth map image (brighter is nearer) used to shade the output. Resized to the output with a triangle filter
+    depth_map: Option<PathBuf>,
+
+    #[structopt(long = "depth-strength", default_value = "1")]
+    /// Strength of the depth map shading
+    depth_strength: f64,
+
+    #[structopt(long = "crop-aspect", parse(try_from_str = "parse_ratio"))]
+    /// Crop the center of the image to an aspect ratio before converting. (e.g. 16:9)
+    crop_aspect: Option<f64>,
+
+    #[structopt(long = "zoom-region", parse(try_from_str = "parse_region"))]
+    /// Region of the image to magnify into an inset. (x,y,width,height in pixels)
+    zoom_region: Option<(u32, u32, u32, u32)>,
+
+    #[structopt(long = "inset-width")]
+    /// Width in characters of the zoom inset. (Defaults to a third of the output)
+    inset_width: Option<u32>,
+
+    #[structopt(long = "inset-position", default_value = "bottom-right")]
+    /// Corner to place the zoom inset in. (top-left, top-right, bottom-left, bottom-right)
+    inset_position: Corner,
+
+    #[structopt(short = "o", long = "output", parse(from_os_str))]
+    /// Write the converted image to a file instead of stdout
+    output: Option<PathBuf>,
+
+    #[structopt(long = "format", default_value = "ansi")]
+    /// Format of the output. (ansi, json, csv)
+    format: OutputFormat,
+
+    #[structopt(long = "compact")]
+    /// Write JSON without whitespace
+    compact: bool,
+
+    #[structopt(long = "output-encoding", default_value = "utf8")]
+    /// Character encoding of the output file, stdout is always UTF-8. (utf8, latin1, cp437)
+    output_encoding: OutputEncoding,
+
+    #[structopt(long = "append", raw(requires = "\"output\""))]
+    /// Append to the output file instead of overwriting it, separating renders with a form feed
+    append: bool,
+
+    #[structopt(long = "set-title")]
+    /// Set the terminal title to the image's file name and the output size, when stdout is a terminal.
+    /// The title stays changed after rascii exits, until something else sets it
+    set_title: bool,
+
+    #[structopt(long = "color-cycle", raw(requires = "\"color\"", conflicts_with = "\"output\""))]
+    /// Animate the output by rotating the hue of every color through a full cycle
+    color_cycle: bool,
+
+    #[structopt(long = "cycle-speed", default_value = "10")]
+    /// Degrees the hue of --color-cycle rotates each frame
+    cycle_speed: f64,
+
+    #[structopt(long = "loop")]
+    /// Repeat --color-cycle until interrupted
+    loop_cycle: bool,
+
+    #[structopt(long = "bell")]
+    /// Ring the terminal bell on stderr when done
+    bell: bool,
+
+    #[structopt(long = "on-complete")]
+    /// Shell command to run when done. (Receives the paths of written files as arguments, e.g. "$1")
+    on_complete: Option<String>,
+
+    #[structopt(long = "output-stats")]
+    /// Print a summary of the conversion to stderr
+    output_stats: bool,
+
+    #[structopt(long = "stats-json", parse(from_os_str))]
+    /// Write the summary of the conversion and the arguments used to this file as JSON
+    stats_json: Option<PathBuf>,
+
+    #[structopt(long = "downsample-report")]
+    /// Print how the image is divided into cells, and how many pixels are left out, to stderr
+    downsample_report: bool,
+
+    #[structopt(long = "print-char-histogram")]
+    /// Print how often each character was used to stderr
+    print_char_histogram: bool,
+
+    #[structopt(long = "ansi-input")]
+    /// Treat the input as ANSI art to recolor rather than an image. (Implied by .ans and .ansi files)
+    ansi_input: bool,
+
+    /// Path of image file to convert, or - to read from stdin
     #[structopt(name = "IMAGE", parse(from_os_str))]
     image: PathBuf,
 }
 
+impl Opt {
+    /// Whether to print the logo and progress to stdout, which JSON or CSV written there would be mixed up with
+    fn show_progress(&self) -> bool {
+        !self.quiet && (self.format == OutputFormat::Ansi || self.output.is_some())
+    }
+
+    /// Rings of tiles around the edge left out of the output
+    fn border(&self) -> u32 {
+        // Every pixel needs its own character
+        if self.force_square_pixels { 0 } else { 1 }
+    }
+
+    /// Distance used to snap to the palette, left optional so it can require --color
+    fn color_distance(&self) -> ColorDistance {
+        self.color_distance.unwrap_or(ColorDistance::WeightedRgb)
+    }
+}
+
 fn main() -> Result<(), Box<dyn Error>> {
     let mut stdout = StandardStream::stdout(ColorChoice::Always);
 
-    // LOGO
-    stdout.set_color(ColorSpec::new().set_fg(Some(Color::Red)))?;
-    writeln!(&mut stdout, "{}", LOGO)?;
-    stdout.set_color(ColorSpec::new().set_fg(Some(Color::White)))?;
-
     let opt = Opt::from_args();
 
-    stdout.set_color(ColorSpec::new().set_fg(Some(Color::Green)))?;
+    if opt.output.is_none() && opt.output_encoding != OutputEncoding::Utf8 {
+        warn(opt.strict, format!("--output-encoding {} only applies to --output, writing stdout as utf8", opt.output_encoding))?;
+    }
 
-    // Load image
-    write!(&mut stdout, "Loading Image...")?;
-    let mut rascii = Rascii::from_opt(&opt)?;
-    writeln!(&mut stdout, "   Done!")?;
+    if opt.show_progress() {
+        // LOGO
+        stdout.set_color(ColorSpec::new().set_fg(Some(Color::Red)))?;
+        writeln!(&mut stdout, "{}", LOGO)?;
+        stdout.set_color(ColorSpec::new().set_fg(Some(Color::Green)))?;
+    }
 
-    // Convert image to ASCII
-    write!(&mut stdout, "ASCIIfying...")?;
-    let output = rascii.run()?;
-    writeln!(&mut stdout, "   Done!\n")?;
+    let (output, elapsed) = if opt.ansi_input || is_ansi_file(&opt.image) {
+        recolor_ansi(&opt)?
+    }
+    else {
+        convert(&opt, &mut stdout)?
+    };
 
     stdout.flush()?;
 
-    stdout.set_color(ColorSpec::new().set_fg(Some(Color::White)))?;
+    if let Some(path) = &opt.palette_output {
+        palette::write(path, output.iter().flatten().map(|(_, color)| color.to_rgb()))?;
+    }
 
-    for row in output {
-        for col in row {
-            if opt.color {
-                let (r,g,b) = match col.1 {
-                    RasciiColor::RGB(r,g,b) => (r,g,b),
-                    _ => (0,0,0)
-                };
+    if opt.output_stats && !opt.quiet {
+        eprintln!("{}", RasciiStats::from_output(&output, elapsed));
+    }
+
+    if let Some(path) = &opt.stats_json {
+        let mut file = File::create(path)?;
+        RasciiStats::from_output(&output, elapsed).to_json().write(&mut file, opt.compact)?;
+    }
+
+
+    // Left set once rascii exits, so the title keeps naming the image on screen
+    if opt.set_title && io::stdout().is_terminal() {
+        write!(&mut stdout, "\x1b]0;{}\x07", title(&opt, &output))?;
+    }
 
-                if opt.bg {
-                    stdout.set_color(ColorSpec::new().set_fg(Some(Color::Rgb(255 - r, 255 - g, 255 -b))))?;
-                    stdout.set_color(ColorSpec::new().set_bg(Some(Color::Rgb(r,g,b))))?;
+    match &opt.output {
+        Some(path) => {
+            let mut lost = String::new();
+            for (c, _) in output.iter().flatten() {
+                if !opt.output_encoding.can_encode(*c) && !lost.contains(*c) {
+                    lost.push(*c);
                 }
-                else {
-                    stdout.set_color(ColorSpec::new().set_fg(Some(Color::Rgb(r,g,b))))?;
+            }
+            if !lost.is_empty() {
+                let message = format!("{} can't represent '{}'", opt.output_encoding, lost);
+                if opt.strict {
+                    return Err(message.into());
                 }
+                warn(false, format!("{}, writing them as '{}'", message, encoding::REPLACEMENT as char))?;
             }
-            write!(&mut stdout, "{}", col.0)?;
-            
+
+            let mut file = Ansi::new(Encoder::new(open_output(path, opt.append)?, opt.output_encoding));
+            write_output(&mut file, output, &opt)?;
+            file.flush()?;
         }
-        writeln!(&mut stdout, "")?;
+        None => {
+            if opt.format == OutputFormat::Ansi {
+                stdout.set_color(ColorSpec::new().set_fg(Some(Color::White)))?;
+            }
+            if opt.color_cycle {
+                color_cycle(&mut stdout, output, &opt)?;
+            }
+            else {
+                write_output(&mut stdout, output, &opt)?;
+            }
+        }
+    }
+
+    // Kept out of stdout, which may be JSON, CSV or a file
+    if opt.bell {
+        eprint!("\x07");
+    }
+
+    if let Some(command) = &opt.on_complete {
+        run_hook(command, &opt)?;
     }
 
     Ok(())
 
 }
 
-struct Rascii {
-    /// Image
-    pub image: RgbImage,
-    /// Image dimensions
-    pub dim: (u32, u32),
-    /// RasciiColored output
-    pub color: bool,
-    pub depth: u8,
-    /// Enable braille mode
-    pub braille: bool
-}
+/// Load and convert the image, reporting progress on `stdout`
+fn convert(opt: &Opt, stdout: &mut StandardStream) -> Result<(RasciiOutput, Duration), Box<dyn Error>> {
+    // Load image
+    if opt.show_progress() {
+        write!(stdout, "Loading Image...")?;
+    }
+    let mut rascii = Rascii::from_opt(opt)?;
+    if opt.show_progress() {
+        writeln!(stdout, "   Done!")?;
+    }
 
-impl Rascii {
-    /// Convert CLI options to a Rascii instance
-    pub fn from_opt(opt: &Opt) -> Result<Self, Box<dyn Error>> {
-        let im: DynamicImage = image::open(&Path::new(&opt.image))?;
-        let im = im.to_rgb();
-        let aspect = im.height() as f64 / im.width() as f64;
-        let height = match opt.height {
-            Some(height) => height,
-            None => (opt.width as f64 * aspect) as u32
-        };
+    if opt.downsample_report && !opt.quiet {
+        eprintln!("{}", rascii.downsample_report());
+    }
 
-        Ok(Rascii {
-            image: im,
-            dim: (opt.width, height),
-            depth: opt.depth,
-            color: opt.color,
-            braille: opt.braille
-        })
+    // Convert image to ASCII
+    if opt.show_progress() {
+        write!(stdout, "ASCIIfying...")?;
+    }
+    let start = Instant::now();
+    let mut output = rascii.run()?;
+    if let Some(region) = opt.zoom_region {
+        let drawn = rascii.dim.0 - 2 * rascii.border;
+        let mut width = opt.inset_width.unwrap_or(drawn / 3);
+        if width > drawn {
+            warn(opt.strict, format!("inset width {} is wider than the output, using {}", width, drawn))?;
+            width = drawn;
+        }
+        let inset = rascii.inset(region, width)?.run()?;
+        composite(&mut output, inset, opt.inset_position);
+    }
+    let elapsed = start.elapsed();
+    if opt.show_progress() {
+        write!(stdout, "{}", PROGRESS_END)?;
     }
 
-    /// Convert the image to rascii based on the settings provided
-    pub fn run(&mut self) -> Result<RasciiOutput, Box<dyn Error>> {
-        let mut output: RasciiOutput = Vec::new(); 
-        // Dimensions of image
-        let (width, height) = self.image.dimensions();
+    if opt.print_char_histogram && !opt.quiet {
+        print_char_histogram(&output, &rascii.glyphs());
+    }
 
-        // Get tile dimensions
-        let tile_w = width / self.dim.0 as u32;
-        let tile_h = height / self.dim.1 as u32;
+    Ok((output, elapsed))
+}
 
-        
-        // Convert image to image chunks based on dimensions.
-        for ty in 1..self.dim.1 -1 {
-            let mut row_tiles = Vec::new();
+/// Read the palette to snap colors to, without any color reserved for the background
+fn load_palette(opt: &Opt) -> Result<Vec<palette::Rgb>, Box<dyn Error>> {
+    let mut colors = match &opt.palette {
+        Some(path) => palette::read(path)?,
+        None => return Ok(Vec::new())
+    };
 
-            for tx in 1..self.dim.0 - 1 {
+    if let Some(background) = opt.palette_lock_background {
+        colors.retain(|color| *color != background);
+        if colors.is_empty() {
+            return Err("--palette-lock-background leaves no other colors in the palette".into());
+        }
+    }
 
-                let mut tile_pixel_data = Vec::with_capacity((tile_w * tile_h) as usize);
-                // per tile
-                for px in 0..tile_w {
-                    for py in 0..tile_h {
-                        let pixel_data = self.image.get_pixel(px + (tx * tile_w), py + (ty * tile_h)).data;
+    Ok(colors)
+}
 
-                        let color: RasciiColor;
-                        if self.color {
-                            color = RasciiColor::RGB(pixel_data[0], pixel_data[1], pixel_data[2])
-                        }
-                        else {
-                            let y = RasciiColor::RGB(pixel_data[0], pixel_data[1], pixel_data[2]).to_grayscale();
-                            color = RasciiColor::Grayscale(y as u8);
-                        }
+/// Whether the input is read from stdin rather than a file
+fn reads_stdin(opt: &Opt) -> bool {
+    opt.image == Path::new("-")
+}
 
-                        tile_pixel_data.push(color);
+/// Read the whole input, from stdin if the path is -
+fn read_input(opt: &Opt) -> io::Result<Vec<u8>> {
+    if reads_stdin(opt) {
+        let mut bytes = Vec::new();
+        io::stdin().read_to_end(&mut bytes)?;
+        Ok(bytes)
+    }
+    else {
+        fs::read(&opt.image)
+    }
+}
 
-                    }
-                }
+/// Whether `path` looks like ANSI art rather than an image
+fn is_ansi_file(path: &Path) -> bool {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some(ext) => ext.eq_ignore_ascii_case("ans") || ext.eq_ignore_ascii_case("ansi"),
+        None => false
+    }
+}
 
-                let avg: RasciiColor;
-                let ascii_char: char;
-                if self.color {
-                    avg = RasciiColor::RGB(
-                       (tile_pixel_data.iter().fold(0usize, |sum, x| {sum + match x { RasciiColor::RGB(r,_,_)=> *r as usize, _ => 0 }}) / tile_pixel_data.len()) as u8,
-                       (tile_pixel_data.iter().fold(0usize, |sum, x| {sum + match x { RasciiColor::RGB(_,g,_)=> *g as usize, _ => 0 }}) / tile_pixel_data.len()) as u8,
-                       (tile_pixel_data.iter().fold(0usize, |sum, x| {sum + match x { RasciiColor::RGB(_,_,b)=> *b as usize, _ => 0 }}) / tile_pixel_data.len()) as u8
-                    );
-                    if self.depth > 10 {
-                        let index = (avg.to_grayscale() as f64/ 255.0) * 67.0;
-                        let chars = GSCALE_70.chars().collect::<Vec<char>>();
-                        ascii_char = chars[index as usize];
-                    }
-                    else {
-                        let index = (avg.to_grayscale() as f64/ 255.0) * 9.0;
-                        ascii_char = GSCALE_10[index as usize];
-                    }
-                }
-                else {
-                    avg = RasciiColor::Grayscale((tile_pixel_data.iter().fold(0usize, |sum, x| {sum + match x { RasciiColor::Grayscale(x)=> *x as usize, _ => 0 } }) as usize / tile_pixel_data.len()) as u8);
-                    let x = match avg {
-                        RasciiColor::Grayscale(x) => x,
-                        _ => 0
-                    };
-                    if self.depth > 10 {
-                        let index = (x as f64/ 255.0) * 67.0;
-                        let chars = GSCALE_70.chars().collect::<Vec<char>>();
-                        ascii_char = chars[index as usize];
-                    }
-                    else {
-                        let index = (x as f64/ 255.0) * 9.0;
-                        ascii_char = GSCALE_10[index as usize];
-                    }
-                }
+/// Parse existing ANSI art and recolor it according to `opt`
+fn recolor_ansi(opt: &Opt) -> Result<(RasciiOutput, Duration), Box<dyn Error>> {
+    let start = Instant::now();
+    // Classic ANSI art is CP437 rather than UTF-8
+    let text = String::from_utf8(read_input(opt)?).unwrap_or_else(|e| encoding::decode_cp437(e.as_bytes()));
+    let palette = load_palette(opt)?;
 
-                row_tiles.push((
-                        ascii_char, avg
-                ));
-            } 
+    let output = ansi::parse(&text).into_iter().map(|row| {
+        row.into_iter().map(|(c, rgb)| {
+            let rgb = match (c, opt.palette_lock_background, palette::nearest(&palette, rgb, opt.color_distance())) {
+                (' ', Some(background), _) => background,
+                (_, _, Some(i)) => palette[i],
+                _ => rgb
+            };
+            let color = RasciiColor::RGB(rgb.0, rgb.1, rgb.2);
 
-            output.push(row_tiles);
+            if opt.color {
+                (c, color)
+            }
+            else {
+                (c, RasciiColor::Grayscale(color.to_grayscale(opt.grayscale_method)))
+            }
+        }).collect()
+    }).collect();
+    let elapsed = start.elapsed();
+
+    // There's no ramp behind existing art
+    if opt.print_char_histogram && !opt.quiet {
+        print_char_histogram(&output, &[]);
+    }
+
+    Ok((output, elapsed))
+}
+
+/// Write `output` to `out` in the format given by `opt`
+fn write_output<W: WriteColor>(out: &mut W, output: RasciiOutput, opt: &Opt) -> io::Result<()> {
+    match opt.format {
+        OutputFormat::Ansi => render(out, output, opt),
+        OutputFormat::Json => grid_json(&output).write(out, opt.compact),
+        OutputFormat::Csv => grid_csv(out, &output, opt.grayscale_method)
+    }
+}
+
+/// Write each character of `output` as a CSV line of `x,y,char,r,g,b,luminance`, after a header
+fn grid_csv<W: Write>(out: &mut W, output: &RasciiOutput, method: GrayscaleMethod) -> io::Result<()> {
+    writeln!(out, "x,y,char,r,g,b,luminance")?;
+
+    for (y, row) in output.iter().enumerate() {
+        for (x, (c, color)) in row.iter().enumerate() {
+            let (r,g,b) = color.to_rgb();
+            let c = match c {
+                '"' => "\"\"\"\"".to_string(),
+                ',' | ' ' => format!("\"{}\"", c),
+                c => c.to_string()
+            };
+            writeln!(out, "{},{},{},{},{},{},{}", x, y, c, r, g, b, color.to_grayscale(method))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// JSON object holding the dimensions of `output` and each of its characters and colors
+fn grid_json(output: &RasciiOutput) -> Json {
+    let rows = output.iter().map(|row| {
+        Json::Array(row.iter().map(|(c, color)| {
+            let (r,g,b) = color.to_rgb();
+            Json::Object(vec![
+                ("char", Json::String(c.to_string())),
+                ("color", Json::Array(vec![Json::Number(r as f64), Json::Number(g as f64), Json::Number(b as f64)]))
+            ])
+        }).collect())
+    }).collect();
+
+    Json::Object(vec![
+        ("width", Json::Number(output.first().map_or(0, |row| row.len()) as f64)),
+        ("height", Json::Number(output.len() as f64)),
+        ("rows", Json::Array(rows))
+    ])
+}
+
+/// Write `output` to `out`, coloring it according to `opt`
+fn render<W: WriteColor>(out: &mut W, output: RasciiOutput, opt: &Opt) -> io::Result<()> {
+    let mut spec = ColorSpec::new();
+    // Only change colors when they differ from the last character's
+    let mut current = None;
+
+    for row in output {
+        for col in row {
+            if opt.color {
+                let (r,g,b) = match col.1 {
+                    RasciiColor::RGB(r,g,b) => (r,g,b),
+                    _ => (0,0,0)
+                };
+
+                if current != Some((r,g,b)) {
+                    if opt.bg {
+                        spec.set_fg(Some(Color::Rgb(255 - r, 255 - g, 255 -b)));
+                        spec.set_bg(Some(Color::Rgb(r,g,b)));
+                    }
+                    else {
+                        spec.set_fg(Some(Color::Rgb(r,g,b)));
+                    }
+                    out.set_color(&spec)?;
+                    current = Some((r,g,b));
+                }
+            }
+            write!(out, "{}", col.0)?;
+            
+        }
+        writeln!(out)?;
+
+        if opt.chunk_output {
+            out.flush()?;
+            if opt.chunk_delay > 0 {
+                thread::sleep(Duration::from_millis(opt.chunk_delay));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Open the output file, either truncating it or appending after a separator
+fn open_output(path: &Path, append: bool) -> io::Result<File> {
+    if !append {
+        return File::create(path);
+    }
+
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    if file.metadata()?.len() > 0 {
+        file.write_all(RENDER_SEPARATOR)?;
+    }
+
+    Ok(file)
+}
+
+/// Run the `--on-complete` command with the paths of any written files as arguments
+fn run_hook(command: &str, opt: &Opt) -> Result<(), Box<dyn Error>> {
+    if command.trim().is_empty() {
+        return Err("--on-complete command is empty".into());
+    }
+
+    // Through the shell so quoting works, `sh` fills in $0 ahead of the paths
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .arg("sh")
+        .args(opt.output.iter().chain(opt.palette_output.iter()).chain(opt.stats_json.iter()))
+        .status()?;
+
+    if !status.success() {
+        return Err(format!("--on-complete command '{}' failed ({})", command, status).into());
+    }
+
+    Ok(())
+}
+
+/// Play `output` with its hues rotating, redrawing it in place, and finish on the original colors
+fn color_cycle(stdout: &mut StandardStream, output: RasciiOutput, opt: &Opt) -> Result<(), Box<dyn Error>> {
+    if opt.format != OutputFormat::Ansi {
+        return Err("--color-cycle can only animate ansi output".into());
+    }
+    if !(opt.cycle_speed > 0.0 && opt.cycle_speed.is_finite()) {
+        return Err(format!("--cycle-speed {} must be a positive number of degrees", opt.cycle_speed).into());
+    }
+
+    let frames = (360.0 / opt.cycle_speed).ceil() as u32;
+    let rotated = |degrees: f64| -> RasciiOutput {
+        output.iter().map(|row| row.iter().map(|(c, color)| (*c, color.rotate_hue(degrees))).collect()).collect()
+    };
+    let redraw = |stdout: &mut StandardStream, frame: RasciiOutput, first: bool| -> io::Result<()> {
+        // Move back up to the first line of the last frame
+        if !first && !frame.is_empty() {
+            write!(stdout, "\x1b[{}F", frame.len())?;
+        }
+        render(stdout, frame, opt)?;
+        stdout.flush()
+    };
+
+    let mut first = true;
+    loop {
+        for frame in 0..frames {
+            redraw(stdout, rotated(frame as f64 * opt.cycle_speed), first)?;
+            first = false;
+            thread::sleep(CYCLE_FRAME_DELAY);
+        }
+
+        if !opt.loop_cycle {
+            break;
+        }
+    }
+
+    redraw(stdout, output, first)?;
+    Ok(())
+}
+
+/// Terminal title naming the image and size of `output`
+fn title(opt: &Opt, output: &RasciiOutput) -> String {
+    let name = match opt.image.file_name() {
+        Some(name) if !reads_stdin(opt) => name.to_string_lossy().into_owned(),
+        _ => "stdin".to_string()
+    };
+    let cols = output.first().map_or(0, |row| row.len());
+
+    // Control characters would end the escape sequence early
+    format!("{} ({}x{})", name, cols, output.len()).chars().filter(|c| !c.is_control()).collect()
+}
+
+/// Report an adjusted option, or fail if `strict`
+fn warn(strict: bool, message: String) -> Result<(), Box<dyn Error>> {
+    if strict {
+        return Err(message.into());
+    }
+
+    eprintln!("Warning: {}", message);
+    Ok(())
+}
+
+/// Print a bar chart of how many times each character of `output` is used to stderr, most used first,
+/// followed by the characters of `ramp` that weren't used
+fn print_char_histogram(output: &RasciiOutput, ramp: &[char]) {
+    const BAR_WIDTH: usize = 40;
+
+    let mut counts: Vec<(char, usize)> = Vec::new();
+    for (c, _) in output.iter().flatten() {
+        match counts.iter_mut().find(|(counted, _)| counted == c) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((*c, 1))
+        }
+    }
+    counts.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+
+    let max = counts.first().map_or(1, |(_, count)| *count);
+    let digits = max.to_string().len();
+    for (c, count) in counts {
+        let bar = (count * BAR_WIDTH).div_ceil(max);
+        eprintln!("'{}' {:>width$} {}", c, count, "#".repeat(bar), width = digits);
+    }
+
+    let unused = ramp::unused(ramp, output.iter().flatten().map(|(c, _)| *c));
+    if !unused.is_empty() {
+        eprintln!("Unused: '{}'", unused.iter().collect::<String>());
+    }
+}
+
+/// Overlay `inset` onto `output` in the given corner
+fn composite(output: &mut RasciiOutput, inset: RasciiOutput, corner: Corner) {
+    let rows = output.len();
+    let cols = output.first().map_or(0, |row| row.len());
+    let inset_rows = inset.len().min(rows);
+    let inset_cols = inset.first().map_or(0, |row| row.len()).min(cols);
+
+    let (top, left) = match corner {
+        Corner::TopLeft => (0, 0),
+        Corner::TopRight => (0, cols - inset_cols),
+        Corner::BottomLeft => (rows - inset_rows, 0),
+        Corner::BottomRight => (rows - inset_rows, cols - inset_cols)
+    };
+
+    for (y, row) in inset.into_iter().take(inset_rows).enumerate() {
+        for (x, cell) in row.into_iter().take(inset_cols).enumerate() {
+            output[top + y][left + x] = cell;
+        }
+    }
+}
+
+/// Mean of `values`, averaged in linear light if `linear` rather than on the encoded values
+fn mean<I: Iterator<Item = u8>>(values: I, linear: bool) -> u8 {
+    let (mut sum, mut count) = (0.0, 0usize);
+    for v in values {
+        sum += if linear { (v as f64 / 255.0).powf(GAMMA) } else { v as f64 / 255.0 };
+        count += 1;
+    }
+
+    let mean = sum / count.max(1) as f64;
+    let encoded = if linear { mean.powf(1.0 / GAMMA) } else { mean };
+    (encoded * 255.0).round().clamp(0.0, 255.0) as u8
+}
+
+/// Average color of the `tile_w` by `tile_h` tile at (`x`, `y`) of `image`
+fn average_color(image: &RgbImage, x: u32, y: u32, tile_w: u32, tile_h: u32, linear: bool) -> RasciiColor {
+    let channel = |i: usize| {
+        let pixels = (y..y + tile_h).flat_map(|py| (x..x + tile_w).map(move |px| (px, py)));
+        mean(pixels.map(|(px, py)| image.get_pixel(px, py).data[i]), linear)
+    };
+
+    RasciiColor::RGB(channel(0), channel(1), channel(2))
+}
+
+/// Summary of a finished conversion
+struct RasciiStats {
+    /// Output dimensions in characters
+    pub dim: (usize, usize),
+    /// Number of distinct characters used
+    pub glyphs: usize,
+    /// Number of distinct colors used
+    pub colors: usize,
+    /// Darkest and brightest luminance in the output
+    pub luminance: (u8, u8),
+    /// Time spent converting
+    pub elapsed: Duration
+}
+
+impl RasciiStats {
+    pub fn from_output(output: &RasciiOutput, elapsed: Duration) -> Self {
+        let mut glyphs = HashSet::new();
+        let mut colors = HashSet::new();
+        let mut luminance = (255, 0);
+
+        for (c, color) in output.iter().flatten() {
+            glyphs.insert(*c);
+            colors.insert(color.to_rgb());
+
+            let l = color.to_grayscale(GrayscaleMethod::Luminance);
+            luminance = (luminance.0.min(l), luminance.1.max(l));
+        }
+
+        if colors.is_empty() {
+            luminance = (0, 0);
+        }
+
+        RasciiStats {
+            dim: (output.first().map_or(0, |row| row.len()), output.len()),
+            glyphs: glyphs.len(),
+            colors: colors.len(),
+            luminance,
+            elapsed
+        }
+    }
+
+    /// The summary along with the arguments rascii was run with
+    pub fn to_json(&self) -> Json {
+        let number = |n: usize| Json::Number(n as f64);
+
+        Json::Object(vec![
+            ("grid", Json::Object(vec![("width", number(self.dim.0)), ("height", number(self.dim.1))])),
+            ("glyphs", number(self.glyphs)),
+            ("colors", number(self.colors)),
+            ("luminance", Json::Object(vec![
+                ("min", Json::Number(self.luminance.0 as f64)),
+                ("max", Json::Number(self.luminance.1 as f64))
+            ])),
+            ("seconds", Json::Number(self.elapsed.as_secs_f64())),
+            ("arguments", Json::Array(std::env::args().skip(1).map(Json::String).collect()))
+        ])
+    }
+}
+
+impl std::fmt::Display for RasciiStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "Grid:       {}x{}", self.dim.0, self.dim.1)?;
+        writeln!(f, "Glyphs:     {}", self.glyphs)?;
+        writeln!(f, "Colors:     {}", self.colors)?;
+        writeln!(f, "Luminance:  {} - {}", self.luminance.0, self.luminance.1)?;
+        write!(f, "Time:       {:.2?}", self.elapsed)
+    }
+}
+
+#[derive(Clone)]
+struct Rascii {
+    /// Image
+    pub image: RgbImage,
+    /// Image dimensions
+    pub dim: (u32, u32),
+    /// Dimensions to letterbox the output to
+    pub frame: Option<(u32, u32)>,
+    /// Cell used for letterbox bars
+    pub letterbox: (char, RasciiColor),
+    /// RasciiColored output
+    pub color: bool,
+    /// Fail instead of adjusting out of range settings
+    pub strict: bool,
+    /// Layout of the sampled tiles
+    pub tile_shape: TileShape,
+    /// Grayscale conversion
+    pub grayscale: GrayscaleMethod,
+    /// Colors to snap output to, empty to keep colors as is
+    pub palette: Vec<palette::Rgb>,
+    /// Color reserved for blank characters
+    pub background: Option<palette::Rgb>,
+    /// Distance used when snapping to `palette`
+    pub distance: ColorDistance,
+    /// Range luminance is rescaled to before picking characters
+    pub luminance_range: (u8, u8),
+    /// Characters from darkest to brightest
+    pub ramp: Vec<char>,
+    /// Expression overriding the ramp index of each character
+    pub expr: Option<Expr>,
+    /// Shape signatures of `ramp`, empty unless antialiasing characters
+    pub signatures: Vec<shape::Signature>,
+    /// Enable braille mode
+    pub braille: bool,
+    /// Dither braille dots rather than thresholding them
+    pub antialiased_braille: bool,
+    /// Vignette strength
+    pub fade_edges: f64,
+    /// Image colors are taken from, the same size as `image`
+    pub color_source: Option<RgbImage>,
+    /// Depth map resized to one pixel per character
+    pub depth_map: Option<GrayImage>,
+    /// Depth shading strength
+    pub depth_strength: f64,
+    /// Strength of local contrast adaptation
+    pub adaptive_contrast: f64,
+    /// Whether tiles are averaged in linear light
+    pub linear_downscale: bool,
+    /// Colors replacing the image's, by luminance
+    pub gradient: Option<Gradient>,
+    /// Take colors from single pixels when tiles are a pixel in size
+    pub color_passthrough: bool,
+    /// Dithering and threshold of 1-bit output
+    pub monochrome: Option<(DitherAlgo, u8)>,
+    /// Alpha channel of `image`, if dithering transparency
+    pub alpha: Option<GrayImage>,
+    /// Rings of tiles around the edge left out of the output
+    pub border: u32
+}
+
+impl Rascii {
+    /// Convert CLI options to a Rascii instance
+    pub fn from_opt(opt: &Opt) -> Result<Self, Box<dyn Error>> {
+        let mut im = Rascii::open_image(opt)?;
+        if let Some(ratio) = opt.crop_aspect {
+            im = Rascii::crop_aspect(im, ratio);
+        }
+        let alpha = if opt.alpha_dither {
+            let rgba = im.to_rgba();
+            Some(ImageBuffer::from_fn(rgba.width(), rgba.height(), |x, y| Luma([rgba.get_pixel(x, y).data[3]])))
+        }
+        else {
+            None
+        };
+        let im = im.to_rgb();
+        let aspect = im.height() as f64 / im.width() as f64;
+        let (mut width, mut requested_height) = Rascii::requested_dim(opt, aspect)?;
+        if let Some(max_line) = opt.max_line {
+            let capped = Rascii::with_border(max_line, opt.border());
+            if width > capped {
+                requested_height = requested_height.map(|height| (height as f64 * capped as f64 / width as f64) as u32);
+                width = capped;
+            }
+            if max_line < MIN_LEGIBLE_WIDTH {
+                warn(opt.strict, format!("--max-line {} leaves little room, the image may be unrecognizable", max_line))?;
+            }
+        }
+        let height = match requested_height {
+            Some(height) => height,
+            None => (width as f64 * aspect) as u32
+        };
+
+        // Fit inside the requested dimensions, padding out to them afterwards
+        let (dim, frame) = match requested_height {
+            _ if opt.force_square_pixels => (im.dimensions(), None),
+            Some(height) if opt.aspect_lock => {
+                let fitted = if (width as f64 * aspect) as u32 > height {
+                    ((height as f64 / aspect) as u32, height)
+                }
+                else {
+                    (width, (width as f64 * aspect) as u32)
+                };
+                (fitted, Some((width, height)))
+            }
+            _ => ((width, height), None)
+        };
+
+        let dim = Rascii::clamp_dim(dim, im.dimensions(), opt.border(), opt.strict)?;
+
+        if opt.color_passthrough && (dim.0 < im.width() || dim.1 < im.height()) {
+            warn(opt.strict, format!("--color-passthrough needs an output of at least {}x{}, averaging colors instead",
+                im.width(), im.height()))?;
+        }
+
+        if opt.min_luminance > opt.max_luminance {
+            return Err(format!("--min-luminance {} is above --max-luminance {}", opt.min_luminance, opt.max_luminance).into());
+        }
+
+        if opt.downscale_gamma_correct && opt.no_downscale_gamma_correct {
+            warn(opt.strict, "--no-downscale-gamma-correct overrides --downscale-gamma-correct".to_string())?;
+        }
+
+        if opt.depth != 10 && opt.depth != 70 {
+            warn(opt.strict, format!("depth {} is not supported, using {}", opt.depth, if opt.depth > 10 { 70 } else { 10 }))?;
+        }
+
+        let letterbox_color = match opt.letterbox_color {
+            Some((r,g,b)) if opt.color => RasciiColor::RGB(r,g,b),
+            Some((r,g,b)) => RasciiColor::Grayscale(RasciiColor::RGB(r,g,b).to_grayscale(opt.grayscale_method)),
+            None if opt.color => RasciiColor::RGB(0,0,0),
+            None => RasciiColor::Grayscale(0)
+        };
+
+        let mut ramp = if opt.depth > 10 {
+            GSCALE_70.chars().collect::<Vec<char>>()
+        }
+        else {
+            GSCALE_10.to_vec()
+        };
+
+        if let Some(blacklist) = &opt.glyph_blacklist {
+            ramp = ramp::without(&ramp, blacklist)?;
+        }
+
+        if let Some(path) = &opt.glyph_weights {
+            let (weighted, ties) = ramp::weighted(&ramp, path)?;
+            if !ties.is_empty() {
+                let ties = ties.into_iter().collect::<String>();
+                warn(opt.strict, format!("'{}' share a weight with another character, their order is arbitrary", ties))?;
+            }
+            ramp = weighted;
+        }
+
+        let signatures = if opt.antialias_chars {
+            shape::signatures(&ramp)
+        }
+        else {
+            Vec::new()
+        };
+
+        let color_source = match &opt.color_from {
+            Some(path) => Some(imageops::resize(&Rascii::open_extra(path, opt)?.to_rgb(), im.width(), im.height(), FilterType::Triangle)),
+            None => None
+        };
+
+        Ok(Rascii {
+            image: im,
+            dim,
+            frame,
+            letterbox: (opt.letterbox_char, letterbox_color),
+            tile_shape: opt.tile_shape,
+            grayscale: opt.grayscale_method,
+            palette: load_palette(opt)?,
+            background: opt.palette_lock_background,
+            distance: opt.color_distance(),
+            luminance_range: (opt.min_luminance, opt.max_luminance),
+            ramp,
+            signatures,
+            expr: opt.expr.clone(),
+            color: opt.color,
+            strict: opt.strict,
+            braille: opt.braille,
+            antialiased_braille: opt.antialiased_braille,
+            fade_edges: opt.fade_edges,
+            color_source,
+            depth_map: match &opt.depth_map {
+                Some(path) => Some(imageops::resize(&Rascii::open_extra(path, opt)?.to_luma(), dim.0, dim.1, FilterType::Triangle)),
+                None => None
+            },
+            depth_strength: opt.depth_strength,
+            adaptive_contrast: opt.adaptive_contrast,
+            linear_downscale: !opt.no_downscale_gamma_correct,
+            gradient: opt.gradient_map.as_deref().map(Gradient::read).transpose()?,
+            color_passthrough: opt.color_passthrough,
+            monochrome: if opt.monochrome_bits { Some((opt.dither_algo, opt.threshold)) } else { None },
+            alpha,
+            border: opt.border()
+        })
+    }
+
+    /// Tiles needed for `cells` drawn characters, with `border` rings of tiles left out around them
+    fn with_border(cells: u32, border: u32) -> u32 {
+        cells.saturating_add(2 * border)
+    }
+
+    /// Width and, if fixed, height asked for in characters
+    fn requested_dim(opt: &Opt, aspect: f64) -> Result<(u32, Option<u32>), Box<dyn Error>> {
+        if let Some((width, height)) = opt.fit_within {
+            let (cols, rows) = if width as f64 * aspect > height as f64 {
+                (((height as f64 / aspect) as u32).max(1), height)
+            }
+            else {
+                (width, ((width as f64 * aspect) as u32).max(1))
+            };
+
+            return Ok((Rascii::with_border(cols, opt.border()), Some(Rascii::with_border(rows, opt.border()))));
+        }
+
+        if let Some(path) = &opt.fit_to_file {
+            let text = fs::read_to_string(path)?;
+            let render = match text.rfind(PROGRESS_END) {
+                Some(i) => &text[i + PROGRESS_END.len()..],
+                None => &text
+            };
+            let render = ansi::parse(render);
+            let (cols, rows) = (render.first().map_or(0, |row| row.len()) as u32, render.len() as u32);
+            if cols == 0 || rows == 0 {
+                return Err(format!("{} is empty, there are no dimensions to fit to", path.display()).into());
+            }
+
+            return Ok((Rascii::with_border(cols, opt.border()), Some(Rascii::with_border(rows, opt.border()))));
+        }
+
+        Ok((opt.width, opt.height))
+    }
+
+    /// Crop the center of `im` to the aspect ratio `ratio` (width / height)
+    fn crop_aspect(mut im: DynamicImage, ratio: f64) -> DynamicImage {
+        let (width, height) = im.dimensions();
+        let (w, h) = if width as f64 / height as f64 > ratio {
+            (((height as f64 * ratio) as u32).max(1), height)
+        }
+        else {
+            (width, ((width as f64 / ratio) as u32).max(1))
+        };
+
+        im.crop((width - w) / 2, (height - h) / 2, w, h)
+    }
+
+    /// Clamp `dim` to between one drawn character inside `border` and one character per pixel of `image_dim`
+    fn clamp_dim(dim: (u32, u32), image_dim: (u32, u32), border: u32, strict: bool) -> Result<(u32, u32), Box<dyn Error>> {
+        let min = Rascii::with_border(1, border);
+        let max = (image_dim.0.max(min), image_dim.1.max(min));
+        let clamped = (dim.0.max(min).min(max.0), dim.1.max(min).min(max.1));
+
+        if clamped != dim {
+            warn(strict, format!("output size {}x{} is out of range for a {}x{} image, using {}x{}",
+                dim.0, dim.1, image_dim.0, image_dim.1, clamped.0, clamped.1))?;
+        }
+
+        Ok(clamped)
+    }
+
+    /// Create a converter for a magnified crop of `region`, `width` characters wide
+    pub fn inset(&self, region: (u32, u32, u32, u32), width: u32) -> Result<Rascii, Box<dyn Error>> {
+        let (x, y, w, h) = region;
+        let (width_px, height_px) = self.image.dimensions();
+        if x.saturating_add(w) > width_px || y.saturating_add(h) > height_px {
+            warn(self.strict, format!("zoom region {},{},{},{} extends past the {}x{} image, clamping it", x, y, w, h, width_px, height_px))?;
+        }
+
+        let mut inset = self.clone();
+        let mut im = imageops::crop(&mut inset.image, x, y, w, h).to_image();
+        if im.width() == 0 || im.height() == 0 {
+            return Err(format!("zoom region {},{},{},{} is outside of the image", x, y, w, h).into());
+        }
+
+        let aspect = im.height() as f64 / im.width() as f64;
+        let rows = ((width as f64 * aspect) as u32).max(1);
+        let dim = (Rascii::with_border(width.max(1), self.border), Rascii::with_border(rows, self.border));
+
+        // Small regions need at least one pixel per character
+        if im.width() < dim.0 || im.height() < dim.1 {
+            im = imageops::resize(&im, dim.0.max(im.width()), dim.1.max(im.height()), FilterType::Nearest);
+        }
+
+        if let Some(source) = inset.color_source.as_mut() {
+            let crop = imageops::crop(source, x, y, w, h).to_image();
+            *source = imageops::resize(&crop, im.width(), im.height(), FilterType::Nearest);
+        }
+        if let Some(alpha) = inset.alpha.as_mut() {
+            let crop = imageops::crop(alpha, x, y, w, h).to_image();
+            *alpha = imageops::resize(&crop, im.width(), im.height(), FilterType::Nearest);
+        }
+
+        inset.image = im;
+        inset.dim = dim;
+        inset.frame = None;
+        inset.depth_map = None;
+        inset.fade_edges = 0.0;
+
+        Ok(inset)
+    }
+
+    /// Open the image, falling back to each format in `--retry-decode-as` on failure
+    fn open_image(opt: &Opt) -> Result<DynamicImage, Box<dyn Error>> {
+        let stdin = reads_stdin(opt);
+        if !stdin && opt.stdin_format.is_some() {
+            warn(opt.strict, "--stdin-format only applies when reading from stdin, ignoring it".to_string())?;
+        }
+        let bytes = read_input(opt)?;
+        let check_size = |format: ImageFormat| check_size(&bytes, format, &opt.image, opt.max_input_pixels);
+
+        // Decode the bytes that were checked, as the one format they were checked as
+        let format = match (stdin, opt.stdin_format) {
+            (true, Some(format)) => Some(format),
+            _ => detect_format(&bytes, &opt.image)
+        };
+
+        let err: Box<dyn Error> = match format {
+            Some(format) => {
+                check_size(format)?;
+                match image::load_from_memory_with_format(&bytes, format) {
+                    Ok(im) => return Ok(im),
+                    Err(err) => err.into()
+                }
+            }
+            None => format!("can't tell the image format of {}, try --retry-decode-as", opt.image.display()).into()
+        };
+
+        for format in &opt.retry_decode_as {
+            check_size(*format)?;
+            if let Ok(im) = image::load_from_memory_with_format(&bytes, *format) {
+                if opt.verbose {
+                    eprintln!("Decoded {} as {:?}", opt.image.display(), format);
+                }
+                return Ok(im);
+            }
+        }
+
+        Err(err)
+    }
+
+    /// Open a secondary image, held to the same `--max-input-pixels` as the main one
+    fn open_extra(path: &Path, opt: &Opt) -> Result<DynamicImage, Box<dyn Error>> {
+        let bytes = fs::read(path)?;
+        let format = detect_format(&bytes, path).ok_or_else(|| format!("can't tell the image format of {}", path.display()))?;
+        check_size(&bytes, format, path, opt.max_input_pixels)?;
+        Ok(image::load_from_memory_with_format(&bytes, format)?)
+    }
+
+    /// Characters of the ramp that the conversion can draw
+    fn glyphs(&self) -> Vec<char> {
+        match (self.monochrome, self.braille) {
+            (Some(_), _) => vec![' ', self.ramp[self.ramp.len() - 1]],
+            // Braille patterns don't come from the ramp
+            (None, true) => Vec::new(),
+            (None, false) => self.ramp.clone()
+        }
+    }
+
+    /// Snap `color` to the nearest color of the palette, if any
+    fn snap(&self, color: RasciiColor) -> RasciiColor {
+        match palette::nearest(&self.palette, color.to_rgb(), self.distance) {
+            Some(i) => {
+                let (r,g,b) = self.palette[i];
+                RasciiColor::RGB(r,g,b)
+            }
+            None => color
+        }
+    }
+
+    /// Rescale luminance `l` (0.0 - 255.0) into the luminance range
+    fn rescale(&self, l: f64) -> f64 {
+        let (min, max) = (self.luminance_range.0 as f64, self.luminance_range.1 as f64);
+        min + l * (max - min) / 255.0
+    }
+
+    /// Pick the character representing luminance `l`
+    fn glyph(&self, l: u8) -> char {
+        let index = (l as f64 / 255.0) * (self.ramp.len() - 1) as f64;
+        self.ramp[index as usize]
+    }
+
+    /// Luminance offset of tile (`tx`, `ty`) from the slope of the depth map, lit from the top left.
+    /// The slope is scaled by the depth strength and then clamped to -1.0 - 1.0. Neighbours past the
+    /// edges of the map are read from the nearest edge pixel instead.
+    fn depth_shade(&self, tx: u32, ty: u32) -> f64 {
+        let depth = match &self.depth_map {
+            Some(depth) => depth,
+            None => return 0.0
+        };
+
+        let (w, h) = depth.dimensions();
+        let at = |x: u32, y: u32| depth.get_pixel(x.min(w - 1), y.min(h - 1)).data[0] as f64 / 255.0;
+
+        // Surfaces coming nearer towards the bottom right face the light and are brightened,
+        // surfaces coming nearer towards the top left face away from it and are darkened
+        let dx = at(tx.saturating_sub(1), ty) - at(tx + 1, ty);
+        let dy = at(tx, ty.saturating_sub(1)) - at(tx, ty + 1);
+
+        (-(dx + dy) / 2.0 * self.depth_strength).clamp(-1.0, 1.0)
+    }
+
+    /// Luminance of the area around each tile, one pixel per tile
+    fn local_average(&self) -> GrayImage {
+        let small = imageops::resize(&self.image, self.dim.0, self.dim.1, FilterType::Triangle);
+        let gray: GrayImage = ImageBuffer::from_fn(self.dim.0, self.dim.1, |x, y| {
+            let p = small.get_pixel(x, y).data;
+            Luma([RasciiColor::RGB(p[0], p[1], p[2]).to_grayscale(self.grayscale)])
+        });
+
+        imageops::blur(&gray, (self.dim.0.max(self.dim.1) as f32 / 16.0).max(1.0))
+    }
+
+    /// Summary of the image size, cell size and the pixels no cell samples
+    pub fn downsample_report(&self) -> String {
+        let (width, height) = self.image.dimensions();
+        let (tile_w, tile_h) = (width / self.dim.0, height / self.dim.1);
+        let remainder = (width % self.dim.0, height % self.dim.1);
+        let drawn = (self.dim.0 - 2 * self.border, self.dim.1 - 2 * self.border);
+        let sampled = (drawn.0 * tile_w) as f64 * (drawn.1 * tile_h) as f64;
+
+        let mut report = format!("Source:     {}x{} pixels\n", width, height);
+        report += &format!("Grid:       {}x{} cells, {}x{} drawn\n", self.dim.0, self.dim.1, drawn.0, drawn.1);
+        report += &format!("Cell:       {}x{} pixels\n", tile_w, tile_h);
+        report += &format!("Remainder:  {} columns, {} rows not covered by any cell\n", remainder.0, remainder.1);
+        report += &format!("Border:     {} columns, {} rows in the undrawn outer cells\n", tile_w * 2 * self.border, tile_h * 2 * self.border);
+        report += &format!("Sampled:    {:.1}% of the image", sampled * 100.0 / (width as f64 * height as f64));
+        report
+    }
+
+    /// Pixel coordinates of the top left corner of tile (`tx`, `ty`)
+    fn tile_origin(&self, tx: u32, ty: u32, tile_w: u32, tile_h: u32) -> (u32, u32) {
+        match self.tile_shape {
+            TileShape::Rect => (tx * tile_w, ty * tile_h),
+            // Shift every other row by half a tile
+            TileShape::Hex => (tx * tile_w + (ty % 2) * tile_w / 2, ty * tile_h)
+        }
+    }
+
+    /// Average luminance (0.0 - 1.0) of each sub-cell of the tile at pixel (`x`, `y`)
+    fn sub_cells(&self, x: u32, y: u32, tile_w: u32, tile_h: u32) -> shape::Signature {
+        let mut cells = [0.0; (shape::GRID * shape::GRID) as usize];
+        cells.copy_from_slice(&self.sub_luminance(x, y, tile_w, tile_h, shape::GRID, shape::GRID));
+        cells
+    }
+
+    /// Average luminance (0.0 - 1.0) of a `cols` by `rows` grid over the tile at pixel (`x`, `y`), row by row.
+    /// Sub-cells smaller than a pixel take the pixel they start in.
+    fn sub_luminance(&self, x: u32, y: u32, tile_w: u32, tile_h: u32, cols: u32, rows: u32) -> Vec<f64> {
+        let mut cells = Vec::with_capacity((cols * rows) as usize);
+
+        for sy in 0..rows {
+            for sx in 0..cols {
+                let x0 = x + sx * tile_w / cols;
+                let y0 = y + sy * tile_h / rows;
+                let x1 = (x + (sx + 1) * tile_w / cols).max(x0 + 1);
+                let y1 = (y + (sy + 1) * tile_h / rows).max(y0 + 1);
+
+                let mut sum = 0usize;
+                for py in y0..y1 {
+                    for px in x0..x1 {
+                        let p = self.image.get_pixel(px, py).data;
+                        sum += RasciiColor::RGB(p[0], p[1], p[2]).to_grayscale(self.grayscale) as usize;
+                    }
+                }
+
+                let count = ((x1 - x0) * (y1 - y0)) as usize;
+                cells.push(sum as f64 / count as f64 / 255.0);
+            }
+        }
+
+        cells
+    }
+
+    /// How far tile (`tx`, `ty`) should be faded towards the background (0.0 - 1.0)
+    fn edge_fade(&self, tx: u32, ty: u32) -> f64 {
+        let cx = (self.dim.0 as f64 - 1.0) / 2.0;
+        let cy = (self.dim.1 as f64 - 1.0) / 2.0;
+        let dx = (tx as f64 - cx) / cx.max(1.0);
+        let dy = (ty as f64 - cy) / cy.max(1.0);
+
+        // Normalized so the corners are at a distance of 1.0
+        let dist = ((dx * dx + dy * dy) / 2.0).sqrt();
+
+        (dist * self.fade_edges).min(1.0)
+    }
+
+    /// Convert the image to rascii based on the settings provided
+    pub fn run(&mut self) -> Result<RasciiOutput, Box<dyn Error>> {
+        let mut output: RasciiOutput = Vec::new(); 
+        // Dimensions of image
+        let (width, height) = self.image.dimensions();
+
+        // Get tile dimensions
+        let tile_w = width / self.dim.0;
+        let tile_h = height / self.dim.1;
+
+        let local_average = if self.adaptive_contrast > 0.0 { Some(self.local_average()) } else { None };
+        let mut ditherer = match self.monochrome {
+            Some((algo, threshold)) => Some(Ditherer::new(algo, threshold, self.dim.0 - 2 * self.border, self.dim.1 - 2 * self.border)),
+            None => None
+        };
+
+        
+        // Convert image to image chunks based on dimensions.
+        for ty in self.border..self.dim.1 - self.border {
+            let mut row_tiles = Vec::new();
+
+            for tx in self.border..self.dim.0 - self.border {
+                let (x, y) = self.tile_origin(tx, ty, tile_w, tile_h);
+
+                // Only sRGB channels are linearized, luminance is already perceptual
+                let mut avg = if self.color {
+                    average_color(&self.image, x, y, tile_w, tile_h, self.linear_downscale)
+                }
+                else if self.linear_downscale {
+                    let color = average_color(&self.image, x, y, tile_w, tile_h, true);
+                    RasciiColor::Grayscale(color.to_grayscale(self.grayscale))
+                }
+                else {
+                    let pixels = (y..y + tile_h).flat_map(|py| (x..x + tile_w).map(move |px| (px, py)));
+                    RasciiColor::Grayscale(mean(pixels.map(|(px, py)| {
+                        let p = self.image.get_pixel(px, py).data;
+                        RasciiColor::RGB(p[0], p[1], p[2]).to_grayscale(self.grayscale)
+                    }), false))
+                };
+
+                let fade = if self.fade_edges > 0.0 { 1.0 - self.edge_fade(tx, ty) } else { 1.0 };
+                if fade < 1.0 {
+                    avg = avg.scale(fade);
+                }
+
+                let base = avg.to_grayscale(self.grayscale) as f64;
+                let mut shade = self.depth_shade(tx, ty);
+                if let Some(local) = &local_average {
+                    // Stretch the difference from the surrounding area
+                    let local = local.get_pixel(tx, ty).data[0] as f64;
+                    let adapted = 128.0 + 2.0 * (base - local);
+                    shade += (adapted - base) * self.adaptive_contrast / 255.0;
+                }
+                let l = self.rescale((base + shade * 255.0).clamp(0.0, 255.0));
+
+                let transparent = match &self.alpha {
+                    Some(alpha) => {
+                        let pixels = (y..y + tile_h).flat_map(|py| (x..x + tile_w).map(move |px| (px, py)));
+                        let coverage = mean(pixels.map(|(px, py)| alpha.get_pixel(px, py).data[0]), false) as f64 / 255.0;
+                        coverage < dither::bayer(tx, ty)
+                    }
+                    None => false
+                };
+
+                let ascii_char = if transparent {
+                    ' '
+                }
+                else if let Some(ditherer) = &mut ditherer {
+                    if ditherer.quantize(tx - self.border, ty - self.border, l) { self.ramp[self.ramp.len() - 1] } else { ' ' }
+                }
+                else if self.braille {
+                    let mut cells = self.sub_luminance(x, y, tile_w, tile_h, braille::COLS, braille::ROWS);
+                    let mut dots = [false; (braille::COLS * braille::ROWS) as usize];
+                    for (i, (cell, dot)) in cells.iter_mut().zip(dots.iter_mut()).enumerate() {
+                        let l = self.rescale((*cell * fade + shade).clamp(0.0, 1.0) * 255.0) / 255.0;
+                        let threshold = if self.antialiased_braille {
+                            // Continue the pattern across cells
+                            let (dx, dy) = (i as u32 % braille::COLS, i as u32 / braille::COLS);
+                            dither::bayer(tx * braille::COLS + dx, ty * braille::ROWS + dy)
+                        }
+                        else {
+                            0.5
+                        };
+                        *dot = l > threshold;
+                    }
+                    braille::from_dots(&dots)
+                }
+                else if let Some(expr) = &self.expr {
+                    let (r,g,b) = avg.to_rgb();
+                    let vars = [
+                        r as f64, g as f64, b as f64, l,
+                        (tx - self.border) as f64, (ty - self.border) as f64,
+                        (self.dim.0 - 2 * self.border) as f64, (self.dim.1 - 2 * self.border) as f64,
+                        self.ramp.len() as f64
+                    ];
+                    let index = expr.eval(&vars)?.round().max(0.0) as usize;
+                    self.ramp[index.min(self.ramp.len() - 1)]
+                }
+                else if !self.signatures.is_empty() && tile_w >= shape::GRID && tile_h >= shape::GRID {
+                    let mut cells = self.sub_cells(x, y, tile_w, tile_h);
+                    for cell in cells.iter_mut() {
+                        *cell = self.rescale((*cell * fade + shade).clamp(0.0, 1.0) * 255.0) / 255.0;
+                    }
+                    self.ramp[shape::best_match(&self.signatures, &cells)]
+                }
+                else {
+                    self.glyph(l as u8)
+                };
+
+                if let (Some(source), true) = (&self.color_source, self.color) {
+                    avg = average_color(source, x, y, tile_w, tile_h, self.linear_downscale).scale(fade);
+                }
+
+                if self.color_passthrough && self.color && tile_w == 1 && tile_h == 1 {
+                    let p = self.color_source.as_ref().unwrap_or(&self.image).get_pixel(x, y).data;
+                    avg = RasciiColor::RGB(p[0], p[1], p[2]).scale(fade);
+                }
+
+                if let (Some(gradient), true) = (&self.gradient, self.color) {
+                    let (r,g,b) = gradient.at(l);
+                    avg = RasciiColor::RGB(r,g,b);
+                }
+
+                if self.color {
+                    avg = match self.background {
+                        Some((r,g,b)) if ascii_char == ' ' => RasciiColor::RGB(r,g,b),
+                        _ => self.snap(avg)
+                    };
+                }
+
+                row_tiles.push((
+                        ascii_char, avg
+                ));
+            } 
+
+            output.push(row_tiles);
 
         }
 
@@ -257,6 +1823,97 @@ impl Rascii {
         
         // Figure out background color and character to show
 
+        if let Some(frame) = self.frame {
+            output = self.letterbox(output, frame);
+        }
+
         Ok(output)
     }
+
+    /// Center `output` within `frame`, filling the remaining space with letterbox bars
+    fn letterbox(&self, output: RasciiOutput, frame: (u32, u32)) -> RasciiOutput {
+        let pad_x = frame.0.saturating_sub(self.dim.0) as usize;
+        let pad_y = frame.1.saturating_sub(self.dim.1) as usize;
+        let cols = output.first().map_or(0, |row| row.len()) + pad_x;
+
+        let bar = || vec![self.letterbox.clone(); cols];
+        let mut framed: RasciiOutput = (0..pad_y / 2).map(|_| bar()).collect();
+
+        for row in output {
+            let mut framed_row = vec![self.letterbox.clone(); pad_x / 2];
+            framed_row.extend(row);
+            framed_row.resize(cols, self.letterbox.clone());
+            framed.push(framed_row);
+        }
+
+        framed.extend((0..pad_y - pad_y / 2).map(|_| bar()));
+        framed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Save `image` to a temporary file and convert it with `args`
+    fn convert_image(name: &str, image: &RgbImage, args: &[&str]) -> RasciiOutput {
+        let path = std::env::temp_dir().join(format!("rascii-test-{}-{}.png", std::process::id(), name));
+        image.save(&path).unwrap();
+
+        let path = path.to_str().unwrap().to_string();
+        let opt = Opt::from_iter(["rascii", "-q"].iter().chain(args).chain([path.as_str()].iter()));
+        let output = Rascii::from_opt(&opt).unwrap().run().unwrap();
+
+        fs::remove_file(&path).unwrap();
+        output
+    }
+
+    #[test]
+    fn csv_quotes_separators() {
+        let output = vec![vec![(',', RasciiColor::RGB(255,0,0)), ('"', RasciiColor::Grayscale(10)), ('a', RasciiColor::Grayscale(0))]];
+        let mut csv = Vec::new();
+        grid_csv(&mut csv, &output, GrayscaleMethod::Average).unwrap();
+
+        assert_eq!(String::from_utf8(csv).unwrap(), "x,y,char,r,g,b,luminance\n0,0,\",\",255,0,0,85\n1,0,\"\"\"\",10,10,10,10\n2,0,a,0,0,0,0\n");
+    }
+
+    #[test]
+    fn force_square_pixels_maps_every_pixel() {
+        let sprite = ImageBuffer::from_fn(4, 4, |x, _| if x < 2 { image::Rgb([255, 0, 0]) } else { image::Rgb([0, 0, 255]) });
+        let output = convert_image("square", &sprite, &["-c", "--force-square-pixels"]);
+
+        assert_eq!(output.len(), 4);
+        assert!(output.iter().all(|row| row.len() == 4));
+        assert_eq!(output[0][0].1.to_rgb(), (255, 0, 0));
+        assert_eq!(output[0][3].1.to_rgb(), (0, 0, 255));
+    }
+
+    #[test]
+    fn force_square_pixels_keeps_tiny_sprites() {
+        let sprite = ImageBuffer::from_pixel(1, 1, image::Rgb([0, 255, 0]));
+        let output = convert_image("tiny", &sprite, &["-c", "--strict", "--force-square-pixels"]);
+
+        assert_eq!(output.len(), 1);
+        assert_eq!(output[0].len(), 1);
+        assert_eq!(output[0][0].1.to_rgb(), (0, 255, 0));
+    }
+
+    #[test]
+    fn color_passthrough_only_applies_at_full_resolution() {
+        let sprite = ImageBuffer::from_fn(16, 16, |x, y| image::Rgb([(x * 13) as u8, (y * 7 + 1) as u8, ((x + y) * 5 + 3) as u8]));
+
+        let full = convert_image("full", &sprite, &["-c", "-w", "16", "-h", "16", "--color-passthrough"]);
+        for (y, row) in full.iter().enumerate() {
+            for (x, (_, color)) in row.iter().enumerate() {
+                let p = sprite.get_pixel(x as u32 + 1, y as u32 + 1).data;
+                assert_eq!(color.to_rgb(), (p[0], p[1], p[2]));
+            }
+        }
+
+        // Downscaled tiles keep their averages
+        let averaged = convert_image("averaged", &sprite, &["-c", "-w", "4", "-h", "4"]);
+        let passthrough = convert_image("passthrough", &sprite, &["-c", "-w", "4", "-h", "4", "--color-passthrough"]);
+        let cells = |output: &RasciiOutput| output.iter().flatten().map(|(c, color)| (*c, color.to_rgb())).collect::<Vec<_>>();
+        assert_eq!(cells(&averaged), cells(&passthrough));
+    }
 }
@@ -0,0 +1,189 @@
+//! Reading, writing and matching against color palettes
+
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
+
+pub type Rgb = (u8, u8, u8);
+
+/// How the distance between two colors is measured
+#[derive(Debug, Clone, Copy)]
+pub enum ColorDistance {
+    /// Euclidean distance in RGB
+    Rgb,
+    /// RGB distance weighted by each channel's contribution to luminance
+    WeightedRgb,
+    /// Euclidean distance in CIELAB (CIE76)
+    Lab
+}
+
+impl FromStr for ColorDistance {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rgb" => Ok(ColorDistance::Rgb),
+            "weighted-rgb" => Ok(ColorDistance::WeightedRgb),
+            "lab" => Ok(ColorDistance::Lab),
+            _ => Err(format!("unknown color distance '{}', expected rgb, weighted-rgb or lab", s))
+        }
+    }
+}
+
+impl ColorDistance {
+    /// Squared distance between `a` and `b`
+    pub fn between(self, a: Rgb, b: Rgb) -> f64 {
+        match self {
+            ColorDistance::Rgb => {
+                let (dr, dg, db) = diff(a, b);
+                dr * dr + dg * dg + db * db
+            }
+            ColorDistance::WeightedRgb => {
+                let (dr, dg, db) = diff(a, b);
+                0.299 * dr * dr + 0.587 * dg * dg + 0.114 * db * db
+            }
+            ColorDistance::Lab => {
+                let (l1, a1, b1) = to_lab(a);
+                let (l2, a2, b2) = to_lab(b);
+                (l1 - l2).powi(2) + (a1 - a2).powi(2) + (b1 - b2).powi(2)
+            }
+        }
+    }
+}
+
+fn diff(a: Rgb, b: Rgb) -> (f64, f64, f64) {
+    (a.0 as f64 - b.0 as f64, a.1 as f64 - b.1 as f64, a.2 as f64 - b.2 as f64)
+}
+
+/// Convert an sRGB color to CIELAB (D65 white point)
+fn to_lab(color: Rgb) -> (f64, f64, f64) {
+    let linear = |c: u8| {
+        let c = c as f64 / 255.0;
+        if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) }
+    };
+    let (r, g, b) = (linear(color.0), linear(color.1), linear(color.2));
+
+    let x = (0.4124 * r + 0.3576 * g + 0.1805 * b) / 0.95047;
+    let y = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+    let z = (0.0193 * r + 0.1192 * g + 0.9505 * b) / 1.08883;
+
+    let f = |t: f64| if t > 0.008856 { t.cbrt() } else { 7.787 * t + 16.0 / 116.0 };
+    let (fx, fy, fz) = (f(x), f(y), f(z));
+
+    (116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz))
+}
+
+/// Index of the color in `palette` closest to `color`
+pub fn nearest(palette: &[Rgb], color: Rgb, distance: ColorDistance) -> Option<usize> {
+    (0..palette.len()).min_by(|a, b| {
+        distance.between(palette[*a], color)
+            .partial_cmp(&distance.between(palette[*b], color))
+            .unwrap()
+    })
+}
+
+/// Read the colors of a GIMP palette (.gpl) file
+pub fn read(path: &Path) -> Result<Vec<Rgb>, Box<dyn Error>> {
+    let contents = fs::read_to_string(path)?;
+    let mut lines = contents.lines();
+
+    if lines.next().map(str::trim) != Some("GIMP Palette") {
+        return Err(format!("{} is not a GIMP palette", path.display()).into());
+    }
+
+    let mut colors = Vec::new();
+    for line in lines {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        // Header fields come before the first color
+        if colors.is_empty() && (line.starts_with("Name:") || line.starts_with("Columns:")) {
+            continue;
+        }
+
+        let channels = line
+            .split_whitespace()
+            .take(3)
+            .map(|c| c.parse::<u8>())
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| format!("invalid palette entry '{}' in {}", line, path.display()))?;
+
+        match channels.as_slice() {
+            [r, g, b] => colors.push((*r, *g, *b)),
+            _ => return Err(format!("invalid palette entry '{}' in {}", line, path.display()).into())
+        }
+    }
+
+    if colors.is_empty() {
+        return Err(format!("{} contains no colors", path.display()).into());
+    }
+
+    Ok(colors)
+}
+
+/// Write every unique color of `colors` to `path` as a GIMP palette
+pub fn write<I: IntoIterator<Item = Rgb>>(path: &Path, colors: I) -> Result<(), Box<dyn Error>> {
+    let mut file = File::create(path)?;
+    let mut seen = HashSet::new();
+
+    writeln!(file, "GIMP Palette")?;
+    writeln!(file, "Name: rascii")?;
+    writeln!(file, "Columns: 0")?;
+    writeln!(file, "#")?;
+
+    for (r,g,b) in colors {
+        if seen.insert((r,g,b)) {
+            writeln!(file, "{:3} {:3} {:3}\t#{:02x}{:02x}{:02x}", r, g, b, r, g, b)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("rascii-palette-{}-{}.gpl", std::process::id(), name))
+    }
+
+    fn read_str(name: &str, contents: &str) -> Result<Vec<Rgb>, Box<dyn Error>> {
+        let path = temp_path(name);
+        fs::write(&path, contents).unwrap();
+        let colors = read(&path);
+        fs::remove_file(&path).unwrap();
+        colors
+    }
+
+    #[test]
+    fn round_trip() {
+        let path = temp_path("round-trip");
+        let colors = vec![(0, 0, 0), (255, 128, 7), (0, 0, 0), (12, 34, 56)];
+        write(&path, colors).unwrap();
+        let read_back = read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(read_back, vec![(0, 0, 0), (255, 128, 7), (12, 34, 56)]);
+    }
+
+    #[test]
+    fn names_may_contain_colons() {
+        let colors = read_str("colons", "GIMP Palette\nName: test\nColumns: 4\n#\n255 0 0\tRed: bright\n0 0 255 Name: blue\n").unwrap();
+        assert_eq!(colors, vec![(255, 0, 0), (0, 0, 255)]);
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        assert!(read_str("header", "Not a palette\n0 0 0\n").is_err());
+        assert!(read_str("short", "GIMP Palette\n0 0\n").is_err());
+        assert!(read_str("range", "GIMP Palette\n0 0 256\n").is_err());
+        assert!(read_str("text", "GIMP Palette\nred green blue\n").is_err());
+        assert!(read_str("late-header", "GIMP Palette\n0 0 0\nColumns: 4\n").is_err());
+        assert!(read_str("empty", "GIMP Palette\nName: empty\n").is_err());
+    }
+}
@@ -0,0 +1,114 @@
+//! Adjustments to the character ramp
+
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+/// Remove every character of `blacklist` from `ramp`
+pub fn without(ramp: &[char], blacklist: &str) -> Result<Vec<char>, String> {
+    let filtered = ramp.iter().copied().filter(|c| !blacklist.contains(*c)).collect::<Vec<char>>();
+
+    if filtered.is_empty() {
+        return Err(format!("blacklisting '{}' leaves no characters to draw with", blacklist));
+    }
+
+    Ok(filtered)
+}
+
+/// Characters of `ramp` that don't appear in `used`, in ramp order
+pub fn unused<I: IntoIterator<Item = char>>(ramp: &[char], used: I) -> Vec<char> {
+    let used = used.into_iter().collect::<HashSet<char>>();
+    ramp.iter().copied().filter(|c| !used.contains(c)).collect()
+}
+
+/// Read `char weight` pairs, one per line, from `path`
+fn read_weights(path: &Path) -> Result<HashMap<char, f64>, Box<dyn Error>> {
+    let contents = fs::read_to_string(path)?;
+    let mut weights = HashMap::new();
+
+    for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+        let mut chars = line.chars();
+        let c = chars.next().unwrap();
+        let weight = chars.as_str().trim().parse::<f64>()
+            .ok()
+            .filter(|weight| weight.is_finite())
+            .ok_or_else(|| format!("invalid glyph weight '{}' in {}", line, path.display()))?;
+
+        if weights.insert(c, weight).is_some() {
+            return Err(format!("'{}' is weighted more than once in {}", c, path.display()).into());
+        }
+    }
+
+    Ok(weights)
+}
+
+/// Reorder `ramp` from lightest to heaviest by the weights in `path`.
+/// Returns the ramp and any characters sharing a weight with another.
+pub fn weighted(ramp: &[char], path: &Path) -> Result<(Vec<char>, Vec<char>), Box<dyn Error>> {
+    let weights = read_weights(path)?;
+
+    let missing = ramp.iter().filter(|c| !weights.contains_key(c)).collect::<String>();
+    if !missing.is_empty() {
+        return Err(format!("{} has no weights for '{}'", path.display(), missing).into());
+    }
+
+    let mut weighted = ramp.to_vec();
+    weighted.sort_by(|a, b| weights[a].partial_cmp(&weights[b]).unwrap());
+
+    let ties = weighted
+        .windows(2)
+        .filter(|pair| weights[&pair[0]] == weights[&pair[1]])
+        .map(|pair| pair[1])
+        .collect();
+
+    Ok((weighted, ties))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn weighted_str(name: &str, ramp: &[char], contents: &str) -> Result<(Vec<char>, Vec<char>), Box<dyn Error>> {
+        let path = std::env::temp_dir().join(format!("rascii-weights-{}-{}.txt", std::process::id(), name));
+        fs::write(&path, contents).unwrap();
+        let weighted = weighted(ramp, &path);
+        fs::remove_file(&path).unwrap();
+        weighted
+    }
+
+    #[test]
+    fn weighted_sorts_lightest_first() {
+        let (ramp, ties) = weighted_str("sort", &['#', ' ', '.'], "# 0.9\n  0\n. 0.2\n").unwrap();
+        assert_eq!(ramp, vec![' ', '.', '#']);
+        assert!(ties.is_empty());
+    }
+
+    #[test]
+    fn weighted_reports_ties() {
+        let (ramp, ties) = weighted_str("ties", &[' ', '.', ':'], "  0\n. 0.5\n: 0.5\n").unwrap();
+        // The sort is stable, so tied characters keep their ramp order
+        assert_eq!(ramp, vec![' ', '.', ':']);
+        assert_eq!(ties, vec![':']);
+    }
+
+    #[test]
+    fn weighted_rejects_missing_and_bad_weights() {
+        assert!(weighted_str("missing", &[' ', '.', '#'], "  0\n# 1\n").is_err());
+        assert!(weighted_str("twice", &[' '], "  0\n  1\n").is_err());
+        assert!(weighted_str("number", &[' '], "  heavy\n").is_err());
+    }
+
+    #[test]
+    fn unused_keeps_ramp_order() {
+        let ramp = [' ', '.', ':', '#', '@'];
+        assert_eq!(unused(&ramp, "@@ ..".chars()), vec![':', '#']);
+        assert!(unused(&ramp, ramp.iter().copied()).is_empty());
+    }
+
+    #[test]
+    fn without_rejects_emptying_the_ramp() {
+        assert_eq!(without(&[' ', '.', '#'], ".").unwrap(), vec![' ', '#']);
+        assert!(without(&[' ', '#'], " #").is_err());
+    }
+}
@@ -0,0 +1,68 @@
+//! Coarse glyph shapes used to pick characters by structure as well as tone
+
+/// Number of sub-cells along each side of a cell
+pub const GRID: u32 = 3;
+
+/// Coverage (0.0 - 1.0) of each sub-cell, row by row
+pub type Signature = [f64; (GRID * GRID) as usize];
+
+/// Ink layout of characters with a distinctive shape.
+/// Characters not listed here are treated as evenly covering their cell.
+fn shape(c: char) -> Option<Signature> {
+    let shape = match c {
+        '.' => [0.0, 0.0, 0.0,  0.0, 0.0, 0.0,  0.0, 1.0, 0.0],
+        ',' => [0.0, 0.0, 0.0,  0.0, 0.0, 0.0,  0.0, 1.0, 0.5],
+        '`' => [1.0, 0.5, 0.0,  0.0, 0.0, 0.0,  0.0, 0.0, 0.0],
+        '^' => [0.5, 1.0, 0.5,  0.0, 0.0, 0.0,  0.0, 0.0, 0.0],
+        '"' => [1.0, 0.0, 1.0,  0.0, 0.0, 0.0,  0.0, 0.0, 0.0],
+        ':' => [0.0, 1.0, 0.0,  0.0, 0.0, 0.0,  0.0, 1.0, 0.0],
+        ';' => [0.0, 1.0, 0.0,  0.0, 0.0, 0.0,  0.0, 1.0, 0.5],
+        '-' | '~' => [0.0, 0.0, 0.0,  1.0, 1.0, 1.0,  0.0, 0.0, 0.0],
+        '_' => [0.0, 0.0, 0.0,  0.0, 0.0, 0.0,  1.0, 1.0, 1.0],
+        '=' => [0.0, 0.0, 0.0,  1.0, 1.0, 1.0,  1.0, 1.0, 1.0],
+        '+' => [0.0, 1.0, 0.0,  1.0, 1.0, 1.0,  0.0, 1.0, 0.0],
+        '|' | 'I' | 'l' | '!' | 'i' | '1' => [0.0, 1.0, 0.0,  0.0, 1.0, 0.0,  0.0, 1.0, 0.0],
+        '/' => [0.0, 0.0, 1.0,  0.0, 1.0, 0.0,  1.0, 0.0, 0.0],
+        '\\' => [1.0, 0.0, 0.0,  0.0, 1.0, 0.0,  0.0, 0.0, 1.0],
+        '(' | '[' | '{' => [0.0, 1.0, 0.5,  1.0, 0.0, 0.0,  0.0, 1.0, 0.5],
+        ')' | ']' | '}' => [0.5, 1.0, 0.0,  0.0, 0.0, 1.0,  0.5, 1.0, 0.0],
+        'L' => [1.0, 0.0, 0.0,  1.0, 0.0, 0.0,  1.0, 1.0, 1.0],
+        'J' => [0.0, 0.0, 1.0,  0.0, 0.0, 1.0,  1.0, 1.0, 1.0],
+        'T' => [1.0, 1.0, 1.0,  0.0, 1.0, 0.0,  0.0, 1.0, 0.0],
+        'Y' => [1.0, 0.0, 1.0,  0.0, 1.0, 0.0,  0.0, 1.0, 0.0],
+        _ => return None
+    };
+
+    Some(shape)
+}
+
+/// Signature of every character in `ramp`.
+/// Each signature keeps the average coverage implied by the character's place in the ramp.
+pub fn signatures(ramp: &[char]) -> Vec<Signature> {
+    let last = (ramp.len().max(2) - 1) as f64;
+
+    ramp.iter().enumerate().map(|(i, c)| {
+        let density = i as f64 / last;
+        let mut signature = [density; (GRID * GRID) as usize];
+
+        if let Some(shape) = shape(*c) {
+            let mean = shape.iter().sum::<f64>() / shape.len() as f64;
+            for (cell, ink) in signature.iter_mut().zip(shape.iter()) {
+                *cell = (density * ink / mean).min(1.0);
+            }
+        }
+
+        signature
+    }).collect()
+}
+
+/// Index of the signature closest to the sub-cell luminances `cells`
+pub fn best_match(signatures: &[Signature], cells: &Signature) -> usize {
+    let error = |signature: &Signature| {
+        signature.iter().zip(cells.iter()).map(|(a, b)| (a - b) * (a - b)).sum::<f64>()
+    };
+
+    (0..signatures.len())
+        .min_by(|a, b| error(&signatures[*a]).partial_cmp(&error(&signatures[*b])).unwrap())
+        .unwrap_or(0)
+}